@@ -0,0 +1,132 @@
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+use crate::{
+  connection::connect,
+  event::TaskStatus,
+  trust::TrustMode,
+  upload::{Upload, UploadOptions},
+};
+
+pub const USAGE: &str = "usage: qft send <host:port> <file | -> [options]
+
+Sends a file, or stdin with -, to the server.
+
+options:
+  --name NAME             name of the uploaded stdin, stdin by default
+  --token TOKEN           access token
+  --tag TAG               destination tag the server routes the upload by
+  --pps N                 packets per second, 20000 by default
+  --block-size N          block size in bytes
+  --codec CODEC           raptorq, reed-solomon or plain
+  --source-blocks N       RaptorQ source blocks per block, 0 to derive from the block length
+  --interleave-depth N    blocks whose packets are interleaved, 4 by default
+  --compression METHOD    none, zstd or lz4
+  --trust MODE            auto, system or ca
+  --passphrase P          encrypt end to end with a passphrase
+  --recipient-key KEY     encrypt end to end for a public key
+  --delta                 only send blocks which changed since the previous version";
+
+const DEFAULT_PPS: u64 = 20000;
+
+struct SendArgs {
+  addr: String,
+  /// None for stdin.
+  path: Option<PathBuf>,
+  name: Option<String>,
+  trust: TrustMode,
+  options: UploadOptions,
+}
+
+fn parse_value<T: FromStr>(arg: &str, value: String) -> Result<T, String>
+where
+  T::Err: Display,
+{
+  value
+    .parse::<T>()
+    .map_err(|e| format!("invalid {} {}: {}", arg, value, e))
+}
+
+fn parse_send_args(args: &[String]) -> Result<SendArgs, String> {
+  let mut positional = vec![];
+  let mut name = None;
+  let mut trust = TrustMode::default();
+  let mut options = UploadOptions {
+    pps: DEFAULT_PPS,
+    ..Default::default()
+  };
+
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    let mut value = || {
+      args
+        .next()
+        .cloned()
+        .ok_or(format!("missing value of {}", arg))
+    };
+    match arg.as_str() {
+      "--name" => name = Some(value()?),
+      "--token" => options.token = Some(value()?),
+      "--tag" => options.tag = Some(value()?),
+      "--pps" => options.pps = parse_value(arg, value()?)?,
+      "--block-size" => options.block_size = Some(parse_value(arg, value()?)?),
+      "--codec" => options.codec = Some(value()?),
+      "--source-blocks" => options.source_blocks = Some(parse_value(arg, value()?)?),
+      "--interleave-depth" => options.interleave_depth = Some(parse_value(arg, value()?)?),
+      "--compression" => options.compression = Some(value()?),
+      "--trust" => trust = value()?.parse()?,
+      "--passphrase" => options.passphrase = Some(value()?),
+      "--recipient-key" => options.recipient_key = Some(value()?),
+      "--delta" => options.delta = Some(true),
+      x if x.starts_with("--") => return Err(format!("unknown option {}", x)),
+      x => positional.push(x.to_string()),
+    }
+  }
+
+  let [addr, path] = <[String; 2]>::try_from(positional).map_err(|_| USAGE.to_string())?;
+  let path = (path != "-").then(|| PathBuf::from(path));
+  if path.is_some() && name.is_some() {
+    return Err("--name only applies to stdin".into());
+  }
+
+  Ok(SendArgs {
+    addr,
+    path,
+    name,
+    trust,
+    options,
+  })
+}
+
+/// Run `qft send`, with certificates and known hosts in `base_path`.
+pub fn send(base_path: PathBuf, args: &[String]) -> Result<(), String> {
+  let args = parse_send_args(args)?;
+
+  tokio::runtime::Runtime::new()
+    .unwrap()
+    .block_on(async move {
+      let (connection, server_fingerprint) = connect(&base_path, args.addr, args.trust).await?;
+      println!("Connected. Server fingerprint: {}", server_fingerprint);
+
+      let upload = match args.path {
+        Some(path) => Upload::file(connection, &path, args.options).await?,
+        None => {
+          let name = args.name.unwrap_or_else(|| "stdin".to_string());
+          Upload::stream(connection, Box::new(tokio::io::stdin()), name, args.options).await?
+        }
+      };
+
+      upload
+        .run(|event| match event.status {
+          TaskStatus::Send => println!(
+            "Sending {}: {} blocks left",
+            event.filename, event.remain_block_count
+          ),
+          TaskStatus::Done => println!(
+            "Sent {}: {} bytes",
+            event.filename,
+            event.file_size.unwrap_or_default()
+          ),
+        })
+        .await
+    })
+}
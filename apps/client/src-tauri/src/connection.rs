@@ -0,0 +1,140 @@
+use std::{path::Path, sync::Arc};
+
+use rust_common::tls::{fingerprint, pem_certs};
+use rustls::client::{ServerCertVerifier, WebPkiVerifier};
+use tokio::{fs, net};
+
+use crate::{
+  client::handle_heartbeat_stream,
+  known_hosts::{KnownHosts, TofuVerifier},
+  trust::{pinned_certs, PinnedVerifier, TrustMode},
+};
+
+/// QUIC transport error of a server refusing new connections (RFC 9000).
+const CONNECTION_REFUSED: u64 = 0x2;
+
+/// Connect to the server at `host:port` and return the connection and the SHA-256 fingerprint of
+/// its certificate. Certificates and known hosts are read from `base_path`.
+///
+/// In the default trust mode, the server is trusted if its certificate is pinned in `cert/`, see
+/// [`pinned_certs`]. Without any, the certificate seen on first connect is recorded in
+/// `known_hosts.json`, and later only the fingerprints recorded for the server are trusted. The
+/// other modes verify the certificate is issued for `host`.
+pub async fn connect(
+  base_path: &Path,
+  addr: String,
+  trust: TrustMode,
+) -> Result<(quinn::Connection, String), String> {
+  let (host, _) = addr.rsplit_once(':').ok_or("missing server port")?;
+  let server_name = host
+    .trim_start_matches('[')
+    .trim_end_matches(']')
+    .to_string();
+  let server_addr = net::lookup_host(&addr)
+    .await
+    .map_err(|e| e.to_string())?
+    .next()
+    .ok_or(format!("failed to resolve {}", addr))?;
+
+  println!("Setup client");
+  let pinned = pinned_certs(&base_path.join("cert"))
+    .await
+    .map_err(|e| format!("failed to read pinned certificates: {}", e))?;
+  let mut known_hosts = KnownHosts::load(base_path.join("known_hosts.json"))
+    .await
+    .map_err(|e| e.to_string())?;
+  let tofu = trust == TrustMode::Auto && pinned.is_empty();
+
+  let verifier: Arc<dyn ServerCertVerifier> = match trust {
+    TrustMode::Auto if !pinned.is_empty() => Arc::new(PinnedVerifier(pinned)),
+    TrustMode::Auto => Arc::new(TofuVerifier {
+      addr: addr.clone(),
+      known: known_hosts.get(&addr),
+    }),
+    TrustMode::System => {
+      let certs = rustls_native_certs::load_native_certs().map_err(|e| e.to_string())?;
+      let mut root_store = rustls::RootCertStore::empty();
+      root_store.add_parsable_certificates(&certs);
+      Arc::new(WebPkiVerifier::new(root_store, None))
+    }
+    TrustMode::Ca => {
+      let ca_path = base_path.join("cert").join("ca.pem");
+      let certs = fs::read(&ca_path)
+        .await
+        .and_then(|x| pem_certs(&x))
+        .map_err(|e| format!("failed to read {}: {}", ca_path.display(), e))?;
+      let mut root_store = rustls::RootCertStore::empty();
+      for cert in certs {
+        root_store
+          .add(&rustls::Certificate(cert))
+          .map_err(|e| e.to_string())?;
+      }
+      Arc::new(WebPkiVerifier::new(root_store, None))
+    }
+  };
+
+  let client_crypto = rustls::ClientConfig::builder()
+    .with_safe_defaults()
+    .with_custom_certificate_verifier(verifier);
+  // The client certificate is optional, for servers requiring client authentication
+  let client_cert = fs::read(base_path.join("cert").join("client.der")).await;
+  let client_key = fs::read(base_path.join("cert").join("client-key.der")).await;
+  let client_crypto = match (client_cert, client_key) {
+    (Ok(cert), Ok(key)) => client_crypto
+      .with_client_auth_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))
+      .map_err(|e| format!("invalid client certificate: {}", e))?,
+    _ => client_crypto.with_no_client_auth(),
+  };
+  let client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+
+  let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap()).unwrap();
+  endpoint.set_default_client_config(client_config);
+
+  let connection = endpoint
+    .connect(server_addr, &server_name)
+    .map_err(|e| e.to_string())?
+    .await
+    .map_err(|e| match e {
+      // The server is at its max connections
+      quinn::ConnectionError::ConnectionClosed(close)
+        if u64::from(close.error_code) == CONNECTION_REFUSED =>
+      {
+        "server refused the connection, it may have too many connections".to_string()
+      }
+      e => e.to_string(),
+    })?;
+
+  let server_fingerprint = connection
+    .peer_identity()
+    .and_then(|x| x.downcast::<Vec<rustls::Certificate>>().ok())
+    .and_then(|x| x.first().map(|cert| fingerprint(&cert.0)))
+    .ok_or("server sent no certificate")?;
+  if tofu && known_hosts.get(&addr).is_empty() {
+    println!("Trust {} on first use: {}", addr, server_fingerprint);
+    known_hosts
+      .insert(addr, server_fingerprint.clone())
+      .await
+      .map_err(|e| e.to_string())?;
+  }
+
+  let connection_clone = connection.clone();
+  tokio::spawn(async move {
+    println!("Open heartbeat stream");
+    let (send, recv) = match connection_clone.open_bi().await {
+      Ok(stream) => stream,
+      Err(e) => {
+        println!("Heartbeat failed: {}", e);
+        return;
+      }
+    };
+
+    match handle_heartbeat_stream(send, recv).await {
+      Err(e) => {
+        println!("Heartbeat failed: {}", e)
+      }
+      _ => {}
+    }
+  });
+
+  Ok((connection, server_fingerprint))
+}
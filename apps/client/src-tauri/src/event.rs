@@ -10,10 +10,11 @@ pub enum TaskStatus {
 #[serde(rename_all = "camelCase")]
 pub struct TaskEvent {
   pub filename: String,
-  pub file_size: u64,
+  /// None while a streamed upload hasn't reached the end of the stream.
+  pub file_size: Option<u64>,
   pub pps: u64,
   pub uuid: String,
-  pub block_count: u32,
+  pub block_count: Option<u32>,
   pub remain_block_count: u32,
   pub status: TaskStatus,
 }
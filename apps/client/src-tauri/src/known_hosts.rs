@@ -0,0 +1,92 @@
+use std::{collections::BTreeMap, path::PathBuf, time::SystemTime};
+
+use anyhow::{Context, Result};
+use rust_common::tls::{fingerprint, fingerprint_matches};
+use rustls::{
+  client::{ServerCertVerified, ServerCertVerifier},
+  Certificate, ServerName,
+};
+use tokio::fs;
+
+/// Fingerprints trusted for a server. Several may be listed by hand while the server rotates
+/// its certificate.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Fingerprints {
+  One(String),
+  Many(Vec<String>),
+}
+
+/// Server certificate fingerprints trusted on first use, by server address. Stored as a JSON
+/// object in `known_hosts.json`, with a fingerprint or a list of them per server.
+pub struct KnownHosts {
+  path: PathBuf,
+  hosts: BTreeMap<String, Fingerprints>,
+}
+
+impl KnownHosts {
+  pub async fn load(path: PathBuf) -> Result<Self> {
+    let hosts = if fs::try_exists(&path).await? {
+      serde_json::from_slice(&fs::read(&path).await?)
+        .with_context(|| format!("Failed to parse {}", path.display()))?
+    } else {
+      BTreeMap::new()
+    };
+    Ok(Self { path, hosts })
+  }
+
+  /// Fingerprints trusted for the server, empty if it is unknown.
+  pub fn get(&self, addr: &str) -> Vec<String> {
+    match self.hosts.get(addr) {
+      Some(Fingerprints::One(fingerprint)) => vec![fingerprint.clone()],
+      Some(Fingerprints::Many(fingerprints)) => fingerprints.clone(),
+      None => vec![],
+    }
+  }
+
+  pub async fn insert(&mut self, addr: String, fingerprint: String) -> Result<()> {
+    self.hosts.insert(addr, Fingerprints::One(fingerprint));
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent).await?;
+    }
+    fs::write(&self.path, serde_json::to_vec_pretty(&self.hosts)?).await?;
+    Ok(())
+  }
+}
+
+/// Accepts any server certificate on first use, and afterwards only the known ones.
+pub struct TofuVerifier {
+  pub addr: String,
+  /// Empty on first use.
+  pub known: Vec<String>,
+}
+
+impl ServerCertVerifier for TofuVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    _intermediates: &[Certificate],
+    _server_name: &ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    let cert_fingerprint = fingerprint(&end_entity.0);
+    if self.known.is_empty()
+      || self
+        .known
+        .iter()
+        .any(|x| fingerprint_matches(&cert_fingerprint, x))
+    {
+      return Ok(ServerCertVerified::assertion());
+    }
+    Err(rustls::Error::General(format!(
+      "certificate of {} changed from {} to {}. Add its fingerprint to the list of {} in \
+       known_hosts.json if the server rotates its certificate",
+      self.addr,
+      self.known.join(", "),
+      cert_fingerprint,
+      self.addr
+    )))
+  }
+}
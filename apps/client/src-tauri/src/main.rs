@@ -1,9 +1,14 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod cli;
 mod client;
 mod commands;
+mod connection;
 mod event;
+mod known_hosts;
+mod trust;
+mod upload;
 
 use tokio::sync::RwLock;
 
@@ -12,9 +17,22 @@ pub use crate::commands::{connect_to_server, send_file};
 pub struct ConnectionState(RwLock<Option<quinn::Connection>>);
 
 fn main() {
+  let context = tauri::generate_context!();
+
+  // `qft send` uploads from the command line, e.g. a pipe, without opening the window
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if args.first().map(String::as_str) == Some("send") {
+    let base_path = tauri::api::path::app_data_dir(context.config()).unwrap();
+    if let Err(e) = cli::send(base_path, &args[1..]) {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    }
+    return;
+  }
+
   tauri::Builder::default()
     .manage(ConnectionState(RwLock::new(None)))
     .invoke_handler(tauri::generate_handler![connect_to_server, send_file])
-    .run(tauri::generate_context!())
+    .run(context)
     .expect("error while running tauri application");
 }
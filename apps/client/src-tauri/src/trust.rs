@@ -0,0 +1,80 @@
+use std::{io, path::Path, str::FromStr, time::SystemTime};
+
+use rustls::{
+  client::{ServerCertVerified, ServerCertVerifier},
+  Certificate, ServerName,
+};
+use tokio::fs;
+
+/// How the server certificate is verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrustMode {
+  /// One of the server certificates in `cert/` if there are any, else trust on first use.
+  #[default]
+  Auto,
+  /// Issued for the server name by a system root CA.
+  System,
+  /// Issued for the server name by a CA of the PEM bundle `cert/ca.pem`.
+  Ca,
+}
+
+impl FromStr for TrustMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "auto" => Ok(Self::Auto),
+      "system" => Ok(Self::System),
+      "ca" => Ok(Self::Ca),
+      _ => Err(format!("unknown trust mode {}", s)),
+    }
+  }
+}
+
+/// Files in `cert/` which are the client's own certificate and key, not pinned ones.
+const CLIENT_CERT_FILES: [&str; 2] = ["client.der", "client-key.der"];
+
+/// Server certificates pinned in `cert_dir`: every `*.der` file other than the client
+/// certificate, e.g. `cert.der`. While the server rotates its certificate, the new one is pinned
+/// next to the old one, so either is trusted whichever the server presents.
+pub async fn pinned_certs(cert_dir: &Path) -> io::Result<Vec<Vec<u8>>> {
+  let mut certs = vec![];
+  let mut entries = match fs::read_dir(cert_dir).await {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(certs),
+    Err(e) => return Err(e),
+  };
+  while let Some(entry) = entries.next_entry().await? {
+    let path = entry.path();
+    let name = entry.file_name().to_string_lossy().to_string();
+    if path.extension().is_some_and(|x| x == "der")
+      && !CLIENT_CERT_FILES.contains(&name.as_str())
+      && entry.file_type().await?.is_file()
+    {
+      certs.push(fs::read(path).await?);
+    }
+  }
+  Ok(certs)
+}
+
+/// Trusts any of the pinned certificates, whatever names they are issued for.
+pub struct PinnedVerifier(pub Vec<Vec<u8>>);
+
+impl ServerCertVerifier for PinnedVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    _intermediates: &[Certificate],
+    _server_name: &ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    if !self.0.contains(&end_entity.0) {
+      return Err(rustls::Error::InvalidCertificate(
+        rustls::CertificateError::UnknownIssuer,
+      ));
+    }
+    Ok(ServerCertVerified::assertion())
+  }
+}
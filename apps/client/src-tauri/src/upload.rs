@@ -0,0 +1,698 @@
+use std::{
+  collections::{HashMap, HashSet},
+  io::Cursor,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+
+use quinn::SendDatagramError;
+use rust_common::{
+  codec::CodecKind,
+  compress::Compression,
+  crypto::{decode_key, BlockCipher, Recipient},
+  delta::{block_hashes, plan_reuse, ReusedBlock, HASH_LEN},
+  error::RequestError,
+  flags::{
+    FLAG_BLOCK_HASHES, FLAG_FILE_DECODE_OK, FLAG_FILE_SIZE, FLAG_OK, FLAG_REQUEST_ID,
+    FLAG_REUSE_BLOCKS, FLAG_UPDATE_PARAMS, FLAG_UPLOAD_COMPLETE, FLAG_UPLOAD_PACKET,
+    UNKNOWN_FILE_SIZE,
+  },
+  params::TransferParams,
+  pipeline::{encode_pipeline, stream_pipeline, EncodedBlock, StreamEvent},
+  schedule::Interleaver,
+};
+use tokio::{
+  fs::{self, File, OpenOptions},
+  io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+  sync::mpsc,
+  time,
+};
+
+use crate::event::{TaskEvent, TaskStatus};
+
+/// Options of an upload, as chosen in the new task dialog or on the command line.
+#[derive(Default)]
+pub struct UploadOptions {
+  pub pps: u64,
+  pub block_size: Option<u64>,
+  pub codec: Option<String>,
+  pub source_blocks: Option<u8>,
+  pub interleave_depth: Option<u8>,
+  pub compression: Option<String>,
+  pub token: Option<String>,
+  /// Destination tag the server routes the upload by.
+  pub tag: Option<String>,
+  pub passphrase: Option<String>,
+  pub recipient_key: Option<String>,
+  pub delta: Option<bool>,
+}
+
+/// Symbol size fitting the connection's current max datagram size.
+fn datagram_symbol_size(connection: &quinn::Connection) -> Result<u16, String> {
+  let max_datagram_size = connection
+    .max_datagram_size()
+    .ok_or("server does not accept datagrams")?;
+
+  TransferParams::symbol_size_for_datagram(max_datagram_size).ok_or(format!(
+    "max datagram size {} is too small",
+    max_datagram_size
+  ))
+}
+
+async fn update_symbol_size(
+  connection: &quinn::Connection,
+  uuid: u128,
+  symbol_size: u16,
+) -> Result<(), String> {
+  let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+  send
+    .write_u8(FLAG_UPDATE_PARAMS)
+    .await
+    .map_err(|e| e.to_string())?;
+  send.write_u128(uuid).await.map_err(|e| e.to_string())?;
+  send
+    .write_u16(symbol_size)
+    .await
+    .map_err(|e| e.to_string())?;
+  send.finish().await.map_err(|e| e.to_string())?;
+
+  if recv.read_u8().await.map_err(|e| e.to_string())? != FLAG_OK {
+    return Err(
+      RequestError::from_code(recv.read_u8().await.map_err(|e| e.to_string())?).to_string(),
+    );
+  }
+
+  Ok(())
+}
+
+/// Blocks of the file which the server already has in its previous version of `filename`.
+async fn plan_delta(
+  connection: &quinn::Connection,
+  path: &Path,
+  filename: &str,
+  token: &str,
+  tag: &str,
+  block_size: u64,
+) -> Result<Vec<ReusedBlock>, String> {
+  let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+  send
+    .write_u8(FLAG_BLOCK_HASHES)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u64(block_size)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u16(token.len() as u16)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(token.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u16(tag.len() as u16)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(tag.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(filename.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send.finish().await.map_err(|e| e.to_string())?;
+
+  if recv.read_u8().await.map_err(|e| e.to_string())? != FLAG_OK {
+    return match RequestError::from_code(recv.read_u8().await.map_err(|e| e.to_string())?) {
+      RequestError::NotFound => Ok(vec![]),
+      e => Err(e.to_string()),
+    };
+  }
+
+  let base_size = recv.read_u64().await.map_err(|e| e.to_string())?;
+  let count = recv.read_u32().await.map_err(|e| e.to_string())?;
+  let mut base_hashes = vec![];
+  for _ in 0..count {
+    let mut hash = [0; HASH_LEN];
+    recv
+      .read_exact(&mut hash)
+      .await
+      .map_err(|e| e.to_string())?;
+    base_hashes.push(hash);
+  }
+
+  let hashes = block_hashes(path, block_size)
+    .await
+    .map_err(|e| e.to_string())?;
+  println!(
+    "Previous version of {} has {} bytes in {} blocks",
+    filename, base_size, count
+  );
+  Ok(plan_reuse(&hashes, &base_hashes))
+}
+
+/// Ask the server to rebuild the blocks from the previous version of `filename`.
+async fn reuse_blocks(
+  connection: &quinn::Connection,
+  uuid: u128,
+  filename: &str,
+  blocks: &[ReusedBlock],
+) -> Result<u32, String> {
+  let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+  send
+    .write_u8(FLAG_REUSE_BLOCKS)
+    .await
+    .map_err(|e| e.to_string())?;
+  send.write_u128(uuid).await.map_err(|e| e.to_string())?;
+  send
+    .write_u16(filename.len() as u16)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(filename.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u32(blocks.len() as u32)
+    .await
+    .map_err(|e| e.to_string())?;
+  for block in blocks {
+    block.write_to(&mut send).await.map_err(|e| e.to_string())?;
+  }
+  send.finish().await.map_err(|e| e.to_string())?;
+
+  if recv.read_u8().await.map_err(|e| e.to_string())? != FLAG_OK {
+    return Err(
+      RequestError::from_code(recv.read_u8().await.map_err(|e| e.to_string())?).to_string(),
+    );
+  }
+  recv.read_u32().await.map_err(|e| e.to_string())
+}
+
+/// Blocks are encrypted for the recipient before encoding, so the server only sees ciphertext.
+fn recipient(options: &UploadOptions) -> Result<Option<Recipient>, String> {
+  let passphrase = options.passphrase.clone().filter(|x| !x.is_empty());
+  let recipient_key = options.recipient_key.as_deref().filter(|x| !x.is_empty());
+  match (passphrase, recipient_key) {
+    (Some(_), Some(_)) => Err("use either a passphrase or a recipient key".into()),
+    (Some(passphrase), None) => Ok(Some(Recipient::Passphrase(passphrase))),
+    (None, Some(key)) => Ok(Some(Recipient::PublicKey(decode_key(key)?))),
+    (None, None) => Ok(None),
+  }
+}
+
+fn transfer_params(
+  connection: &quinn::Connection,
+  options: &UploadOptions,
+  encrypted: bool,
+) -> Result<TransferParams, String> {
+  let params = TransferParams {
+    block_size: options
+      .block_size
+      .unwrap_or(TransferParams::default().block_size),
+    symbol_size: datagram_symbol_size(connection)?,
+    codec: match &options.codec {
+      Some(codec) => codec.parse::<CodecKind>()?,
+      None => CodecKind::default(),
+    },
+    source_blocks: options.source_blocks.unwrap_or(1),
+    encrypted,
+    compression: match &options.compression {
+      Some(compression) => compression.parse::<Compression>()?,
+      None => Compression::None,
+    },
+    interleave_depth: options
+      .interleave_depth
+      .unwrap_or(TransferParams::default().interleave_depth),
+  };
+  params.validate()?;
+  Ok(params)
+}
+
+/// A string sent with a u16 length prefix, empty if unset.
+fn length_prefixed(value: &Option<String>, name: &str) -> Result<String, String> {
+  let value = value.clone().unwrap_or_default();
+  if value.len() > u16::MAX as usize {
+    return Err(format!("{} is too long", name));
+  }
+  Ok(value)
+}
+
+/// Request an upload ID from the server.
+async fn request_upload(
+  connection: &quinn::Connection,
+  file_size: u64,
+  params: &TransferParams,
+  token: &str,
+  encryption_header: &[u8],
+  tag: &str,
+  filename: &str,
+) -> Result<u128, String> {
+  let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+  send
+    .write_u8(FLAG_REQUEST_ID)
+    .await
+    .map_err(|e| e.to_string())?;
+  send.write_u64(file_size).await.map_err(|e| e.to_string())?;
+  params
+    .write_to(&mut send)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u16(token.len() as u16)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(token.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u16(encryption_header.len() as u16)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(encryption_header)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_u16(tag.len() as u16)
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(tag.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send
+    .write_all(filename.as_bytes())
+    .await
+    .map_err(|e| e.to_string())?;
+  send.finish().await.map_err(|e| e.to_string())?;
+
+  if recv.read_u8().await.map_err(|e| e.to_string())? != FLAG_OK {
+    let e = RequestError::from_code(recv.read_u8().await.map_err(|e| e.to_string())?);
+    println!("Upload request rejected: {}", e);
+    return Err(e.to_string());
+  }
+
+  let uuid = recv.read_u128().await.map_err(|e| e.to_string())?;
+  println!("Get upload UUID: {}", uuid);
+  Ok(uuid)
+}
+
+/// Announce the size of a streamed upload once the stream ended.
+async fn announce_size(
+  connection: &quinn::Connection,
+  uuid: u128,
+  file_size: u64,
+) -> Result<(), String> {
+  let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+  send
+    .write_u8(FLAG_FILE_SIZE)
+    .await
+    .map_err(|e| e.to_string())?;
+  send.write_u128(uuid).await.map_err(|e| e.to_string())?;
+  send.write_u64(file_size).await.map_err(|e| e.to_string())?;
+  send.finish().await.map_err(|e| e.to_string())?;
+
+  if recv.read_u8().await.map_err(|e| e.to_string())? != FLAG_OK {
+    return Err(
+      RequestError::from_code(recv.read_u8().await.map_err(|e| e.to_string())?).to_string(),
+    );
+  }
+
+  Ok(())
+}
+
+/// Encoded blocks of a round, from a file or from the stream being read.
+enum BlockSource {
+  File(mpsc::Receiver<EncodedBlock>),
+  Stream(mpsc::Receiver<Result<StreamEvent, String>>),
+}
+
+impl BlockSource {
+  async fn next(&mut self) -> Option<Result<StreamEvent, String>> {
+    match self {
+      Self::File(rx) => rx
+        .recv()
+        .await
+        .map(|x| x.map(|(block_id, packets)| StreamEvent::Block(block_id, packets))),
+      Self::Stream(rx) => rx.recv().await,
+    }
+  }
+}
+
+/// An upload the server accepted.
+pub struct Upload {
+  connection: quinn::Connection,
+  uuid: u128,
+  filename: String,
+  /// `UNKNOWN_FILE_SIZE` until a streamed upload reaches the end of the stream.
+  file_size: u64,
+  pps: u64,
+  params: TransferParams,
+  cipher: Option<Arc<BlockCipher>>,
+  /// Blocks to send in the next round.
+  missing: Vec<u32>,
+  /// The file, or the spool of a streamed upload.
+  file: File,
+  /// Stream read in the first round. Later rounds read lost blocks from the spool.
+  stream: Option<Box<dyn AsyncRead + Unpin + Send>>,
+  spool_path: Option<PathBuf>,
+}
+
+impl Upload {
+  /// Request the upload of the file at `path`.
+  pub async fn file(
+    connection: quinn::Connection,
+    path: &Path,
+    options: UploadOptions,
+  ) -> Result<Self, String> {
+    let recipient = recipient(&options)?;
+    let params = transfer_params(&connection, &options, recipient.is_some())?;
+
+    let file = File::open(path).await.map_err(|e| e.to_string())?;
+    let filename = path
+      .file_name()
+      .ok_or("not a file path")?
+      .to_string_lossy()
+      .to_string();
+    let file_size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let block_count = params.block_count(file_size);
+    let token = length_prefixed(&options.token, "access token")?;
+    let tag = length_prefixed(&options.tag, "destination tag")?;
+
+    let cipher = match recipient {
+      Some(recipient) => {
+        let block_size = params.block_size;
+        let cipher =
+          tokio::task::spawn_blocking(move || BlockCipher::new(&recipient, file_size, block_size))
+            .await
+            .unwrap()
+            .map_err(|e| e.to_string())?;
+        Some(Arc::new(cipher))
+      }
+      None => None,
+    };
+    let encryption_header = cipher.as_ref().map_or(vec![], |x| x.header().to_vec());
+
+    // Only blocks which differ from the previous version on the server are sent. Encrypted
+    // blocks can't be compared by the server.
+    let reused_blocks = if options.delta.unwrap_or(false) && cipher.is_none() && block_count > 0 {
+      match plan_delta(
+        &connection,
+        path,
+        &filename,
+        &token,
+        &tag,
+        params.block_size,
+      )
+      .await
+      {
+        Ok(blocks) => blocks,
+        Err(e) => {
+          println!("Failed to get block hashes, send every block: {}", e);
+          vec![]
+        }
+      }
+    } else {
+      vec![]
+    };
+
+    let uuid = request_upload(
+      &connection,
+      file_size,
+      &params,
+      &token,
+      &encryption_header,
+      &tag,
+      &filename,
+    )
+    .await?;
+
+    // Blocks the server fails to reuse are reported missing and sent in the next round
+    let mut missing: Vec<u32> = (0..block_count).collect();
+    if !reused_blocks.is_empty() {
+      match reuse_blocks(&connection, uuid, &filename, &reused_blocks).await {
+        Ok(count) => {
+          println!(
+            "Server reused {}/{} unchanged blocks",
+            count,
+            reused_blocks.len()
+          );
+          let reused: HashSet<u32> = reused_blocks.iter().map(|x| x.block_id).collect();
+          missing.retain(|x| !reused.contains(x));
+        }
+        Err(e) => println!("Failed to reuse blocks, send every block: {}", e),
+      }
+    }
+
+    Ok(Self {
+      connection,
+      uuid,
+      filename,
+      file_size,
+      pps: options.pps,
+      params,
+      cipher,
+      missing,
+      file,
+      stream: None,
+      spool_path: None,
+    })
+  }
+
+  /// Request the upload of a stream of unknown length, e.g. stdin, as `filename`. Blocks are
+  /// sent as they are read and spooled to a temporary file, from which lost blocks are resent.
+  pub async fn stream(
+    connection: quinn::Connection,
+    stream: Box<dyn AsyncRead + Unpin + Send>,
+    filename: String,
+    options: UploadOptions,
+  ) -> Result<Self, String> {
+    // The encryption header and the block hashes need the whole file up front
+    if recipient(&options)?.is_some() {
+      return Err("streamed uploads can't be encrypted".into());
+    }
+    if options.delta.unwrap_or(false) {
+      return Err("streamed uploads can't be delta uploads".into());
+    }
+    let params = transfer_params(&connection, &options, false)?;
+    let token = length_prefixed(&options.token, "access token")?;
+    let tag = length_prefixed(&options.tag, "destination tag")?;
+
+    let uuid = request_upload(
+      &connection,
+      UNKNOWN_FILE_SIZE,
+      &params,
+      &token,
+      &[],
+      &tag,
+      &filename,
+    )
+    .await?;
+
+    let spool_path =
+      std::env::temp_dir().join(format!("qft-stream-{}-{}", std::process::id(), uuid));
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&spool_path)
+      .await
+      .map_err(|e| format!("failed to create {}: {}", spool_path.display(), e))?;
+
+    Ok(Self {
+      connection,
+      uuid,
+      filename,
+      file_size: UNKNOWN_FILE_SIZE,
+      pps: options.pps,
+      params,
+      cipher: None,
+      missing: vec![],
+      file,
+      stream: Some(stream),
+      spool_path: Some(spool_path),
+    })
+  }
+
+  pub fn event(&self, status: TaskStatus) -> TaskEvent {
+    let size_known = self.file_size != UNKNOWN_FILE_SIZE;
+    TaskEvent {
+      filename: self.filename.clone(),
+      file_size: size_known.then_some(self.file_size),
+      pps: self.pps,
+      uuid: self.uuid.to_string(),
+      block_count: size_known.then(|| self.params.block_count(self.file_size)),
+      remain_block_count: match status {
+        TaskStatus::Send => self.missing.len() as u32,
+        TaskStatus::Done => 0,
+      },
+      status,
+    }
+  }
+
+  /// Send blocks in rounds until the server rebuilt every block.
+  pub async fn run(mut self, on_event: impl Fn(TaskEvent)) -> Result<(), String> {
+    let result = self.send_rounds(&on_event).await;
+    if let Some(spool_path) = &self.spool_path {
+      let _ = fs::remove_file(spool_path).await;
+    }
+    result?;
+
+    on_event(self.event(TaskStatus::Done));
+    Ok(())
+  }
+
+  async fn send_rounds(&mut self, on_event: &impl Fn(TaskEvent)) -> Result<(), String> {
+    if self.file_size == 0 {
+      println!("Empty file created by server");
+      return Ok(());
+    }
+    if self.pps == 0 {
+      return Err("packets per second must be positive".into());
+    }
+
+    let connection = self.connection.clone();
+    let uuid = self.uuid;
+    let encode_workers = std::thread::available_parallelism()
+      .map(|n| n.get())
+      .unwrap_or(1);
+    let start_time = time::Instant::now();
+    let mut interval = time::interval(Duration::from_micros(1000000 / self.pps));
+
+    loop {
+      // Path MTU discovery may have changed the max datagram size since last round
+      let symbol_size = datagram_symbol_size(&connection)
+        .map_err(|e| format!("failed to get symbol size: {}", e))?;
+      if symbol_size != self.params.symbol_size {
+        update_symbol_size(&connection, uuid, symbol_size)
+          .await
+          .map_err(|e| format!("failed to update symbol size: {}", e))?;
+        println!("Max datagram size changed. Use symbol size {}", symbol_size);
+        self.params.symbol_size = symbol_size;
+      }
+
+      on_event(self.event(TaskStatus::Send));
+
+      let file = self
+        .file
+        .try_clone()
+        .await
+        .map_err(|e| format!("failed to open file: {}", e))?;
+      // The workers encode ahead already, so one queued block keeps the interleaver fed. Held in
+      // memory are the blocks being encoded, the queued one and the interleave window.
+      let queued = 1;
+      let streaming = self.stream.is_some();
+      let mut encoded_blocks = match self.stream.take() {
+        Some(stream) => BlockSource::Stream(stream_pipeline(
+          stream,
+          file,
+          0.1,
+          self.params,
+          encode_workers,
+          queued,
+        )),
+        None => BlockSource::File(encode_pipeline(
+          file,
+          self.missing.clone(),
+          0.1,
+          self.params,
+          self.cipher.clone(),
+          encode_workers,
+          queued,
+        )),
+      };
+      let mut interleaver = Interleaver::new(self.params.interleave_depth as usize);
+      let mut encoded_lens = HashMap::new();
+      loop {
+        while !interleaver.is_full() {
+          match encoded_blocks.next().await {
+            Some(Ok(StreamEvent::Block(block_id, encoded))) => {
+              encoded_lens.insert(block_id, encoded.encoded_len);
+              interleaver.push(block_id, encoded.packets);
+            }
+            // Announced before the last block is sent, which the server decodes by the size
+            Some(Ok(StreamEvent::End(file_size))) => {
+              announce_size(&connection, uuid, file_size)
+                .await
+                .map_err(|e| format!("failed to announce file size: {}", e))?;
+              println!("Stream ended after {} bytes", file_size);
+              self.file_size = file_size;
+            }
+            Some(Err(e)) => return Err(format!("failed to encode block: {}", e)),
+            None => break,
+          }
+        }
+
+        let Some((block_id, packet)) = interleaver.next_packet() else {
+          break;
+        };
+
+        let packet_datagram: Vec<u8> = vec![];
+        let mut cur = Cursor::new(packet_datagram);
+        cur.write_u8(FLAG_UPLOAD_PACKET).await.unwrap();
+        cur.write_u128(uuid).await.unwrap();
+        cur.write_u32(block_id).await.unwrap();
+        cur.write_u32(encoded_lens[&block_id]).await.unwrap();
+        cur.write_all(&packet).await.unwrap();
+
+        match connection.send_datagram(cur.into_inner().into()) {
+          Ok(()) => {}
+          // The stream can't be read again, so it is read to the end and the blocks which
+          // didn't fit are resent from the spool next round
+          Err(SendDatagramError::TooLarge) if streaming => {}
+          Err(SendDatagramError::TooLarge) => {
+            println!("Datagram exceeds max datagram size. End round early");
+            break;
+          }
+          Err(e) => return Err(format!("failed to send datagram: {}", e)),
+        }
+
+        interval.tick().await;
+      }
+
+      println!("Upload complete");
+      let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+      send
+        .write_u8(FLAG_UPLOAD_COMPLETE)
+        .await
+        .map_err(|e| e.to_string())?;
+      send.write_u128(uuid).await.map_err(|e| e.to_string())?;
+
+      if recv.read_u8().await.map_err(|e| e.to_string())? == FLAG_FILE_DECODE_OK {
+        println!("Server confirmed decoded successfully");
+        let elapsed = time::Instant::elapsed(&start_time);
+        println!(
+          "Done in {:?}. Average speed: {:.2} MiB/s",
+          elapsed,
+          self.file_size as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64()
+        );
+
+        return Ok(());
+      }
+
+      let missing_block_count = recv.read_u32().await.map_err(|e| e.to_string())?;
+      // Every block arrived, so resending won't help
+      if missing_block_count == 0 {
+        return Err(RequestError::StoreFailed.to_string());
+      }
+      println!(
+        "Server failed to decode. Missing {} blocks. Retry",
+        missing_block_count
+      );
+
+      self.missing.clear();
+      for _ in 0..missing_block_count {
+        self
+          .missing
+          .push(recv.read_u32().await.map_err(|e| e.to_string())?);
+      }
+    }
+  }
+}
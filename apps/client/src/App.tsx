@@ -7,6 +7,7 @@ import {
   Icon,
   Input,
   Progress,
+  Select,
   Spinner,
   Text,
   useDisclosure,
@@ -14,7 +15,7 @@ import {
 import { invoke, shell } from '@tauri-apps/api'
 import { listen } from '@tauri-apps/api/event'
 import { BaseDirectory, createDir, exists } from '@tauri-apps/api/fs'
-import { appDataDir, join, resolve } from '@tauri-apps/api/path'
+import { appDataDir, resolve } from '@tauri-apps/api/path'
 import { filesize } from 'filesize'
 import { useEffect, useState } from 'react'
 import { MdCheckCircleOutline, MdLink, MdList, MdSend } from 'react-icons/md'
@@ -22,26 +23,42 @@ import { toast } from './common/toast'
 import { NewTaskModal } from './modals/NewTaskModal'
 import { Task } from './types/task'
 
+function getProgress(task: Task) {
+  if (task.blockCount === null) {
+    return 0
+  }
+  // Empty files have no blocks to confirm
+  if (task.blockCount === 0) {
+    return task.status === 'done' ? 100 : 0
+  }
+  return ((task.blockCount - task.remainBlockCount) / task.blockCount) * 100
+}
+
 function App() {
   const [serverAddr, setServerAddr] = useState('127.0.0.1:23333')
+  const [trust, setTrust] = useState('auto')
   const [connected, setConnected] = useState(false)
   const [tasks, setTasks] = useState<Task[]>([])
 
   const newTaskModal = useDisclosure()
 
   async function handleConnect() {
-    if (
-      !(await exists(await join('cert', 'cert.der'), {
-        dir: BaseDirectory.AppData,
-      }))
-    ) {
-      toast({ title: '请先配置信任证书', status: 'error' })
+    let fingerprint: string
+    try {
+      fingerprint = await invoke<string>('connect_to_server', {
+        addr: serverAddr,
+        trust,
+      })
+    } catch (e) {
+      toast({ title: `连接失败：${e}`, status: 'error' })
       return
     }
-
-    await invoke('connect_to_server', { addr: serverAddr })
     setConnected(true)
-    toast({ title: '已连接到服务端', status: 'success' })
+    toast({
+      title: '已连接到服务端',
+      description: `证书指纹：${fingerprint}`,
+      status: 'success',
+    })
   }
 
   async function handleOpenCertDir() {
@@ -99,6 +116,16 @@ function App() {
               value={serverAddr}
               onChange={(e) => setServerAddr(e.target.value)}
             />
+            <Select
+              w="200px"
+              size="sm"
+              value={trust}
+              onChange={(e) => setTrust(e.target.value)}
+            >
+              <option value="auto">信任证书或首次连接的证书</option>
+              <option value="system">系统根证书</option>
+              <option value="ca">CA 证书（cert/ca.pem）</option>
+            </Select>
             <Button
               colorScheme="blue"
               leftIcon={<Icon as={MdLink} />}
@@ -187,7 +214,9 @@ function App() {
                     </Flex>
                     <Text fontSize={18}>{task.filename}</Text>
                     <Badge colorScheme="blue" variant="outline">
-                      {filesize(task.fileSize, { standard: 'jedec' })}
+                      {task.fileSize === null
+                        ? '流式上传'
+                        : filesize(task.fileSize, { standard: 'jedec' })}
                     </Badge>
                   </Flex>
                   <Flex px={4} py={2} direction="column" gap={1} fontSize={14}>
@@ -202,34 +231,28 @@ function App() {
                       <Box w="90px" textAlign="right" textColor="GrayText">
                         块总数：
                       </Box>
-                      {task.blockCount}
+                      {task.blockCount ?? '未知'}
                     </Flex>
                     <Flex>
                       <Box w="90px" textAlign="right" textColor="GrayText">
                         已确认块数：
                       </Box>
-                      {task.blockCount - task.remainBlockCount}
+                      {task.blockCount === null
+                        ? '未知'
+                        : task.blockCount - task.remainBlockCount}
                     </Flex>
                     <Flex>
                       <Box w="90px" textAlign="right" textColor="GrayText">
                         确认进度：
                       </Box>
-                      {(
-                        ((task.blockCount - task.remainBlockCount) /
-                          task.blockCount) *
-                        100
-                      ).toFixed(1)}
+                      {getProgress(task).toFixed(1)}
                       %
                     </Flex>
 
                     <Progress
                       my={2}
                       size="sm"
-                      value={
-                        ((task.blockCount - task.remainBlockCount) /
-                          task.blockCount) *
-                        100
-                      }
+                      value={getProgress(task)}
                     />
                   </Flex>
                 </Card>
@@ -1,6 +1,7 @@
 import {
   Alert,
   Button,
+  Checkbox,
   Flex,
   Input,
   Modal,
@@ -10,6 +11,7 @@ import {
   ModalFooter,
   ModalHeader,
   ModalOverlay,
+  Select,
   Slider,
   SliderFilledTrack,
   SliderThumb,
@@ -22,6 +24,9 @@ import { filesize } from 'filesize'
 import { useState } from 'react'
 import { toast } from '../common/toast'
 
+/** Larger blocks must be partitioned into several RaptorQ source blocks. */
+const MAX_BLOCK_SIZE = 16 * 1024 * 1024
+
 export function NewTaskModal({
   isOpen,
   onClose,
@@ -31,9 +36,44 @@ export function NewTaskModal({
 }) {
   const [filePath, setFilePath] = useState('')
   const [pps, setPps] = useState(20000)
+  const [blockSize, setBlockSize] = useState(1024 * 1024)
+  const [codec, setCodec] = useState('raptorq')
+  const [sourceBlocks, setSourceBlocks] = useState(1)
+  const [interleaveDepth, setInterleaveDepth] = useState(4)
+  const [compression, setCompression] = useState('none')
+  const [delta, setDelta] = useState(false)
+  const [token, setToken] = useState('')
+  const [tag, setTag] = useState('')
+  const [encryption, setEncryption] = useState('none')
+  const [passphrase, setPassphrase] = useState('')
+  const [recipientKey, setRecipientKey] = useState('')
+
+  const partitioned = codec === 'raptorq' && sourceBlocks !== 1
+  // Hidden block sizes fall back to the largest one allowed
+  const effectiveBlockSize = partitioned
+    ? blockSize
+    : Math.min(blockSize, MAX_BLOCK_SIZE)
 
   async function handleCreateTask() {
-    await invoke('send_file', { path: filePath, pps })
+    try {
+      await invoke('send_file', {
+        path: filePath,
+        pps,
+        blockSize: effectiveBlockSize,
+        codec,
+        sourceBlocks: codec === 'raptorq' ? sourceBlocks : 1,
+        interleaveDepth,
+        compression: encryption === 'none' ? compression : 'none',
+        token,
+        tag,
+        passphrase: encryption === 'passphrase' ? passphrase : null,
+        recipientKey: encryption === 'recipient' ? recipientKey : null,
+        delta: encryption === 'none' && delta,
+      })
+    } catch (e) {
+      toast({ title: `创建失败：${e}`, status: 'error' })
+      return
+    }
     toast({
       title: '传输任务已创建',
       status: 'success',
@@ -72,6 +112,139 @@ export function NewTaskModal({
                   </Button>
                 </Flex>
               </div>
+              <div>
+                访问令牌
+                <Input
+                  mt={1}
+                  type="password"
+                  placeholder="服务端未配置令牌时留空"
+                  value={token}
+                  onChange={(e) => setToken(e.target.value)}
+                />
+              </div>
+              <div>
+                目标标签
+                <Input
+                  mt={1}
+                  placeholder="服务端按标签选择接收目录，可留空"
+                  value={tag}
+                  onChange={(e) => setTag(e.target.value)}
+                />
+              </div>
+              <div>
+                端到端加密
+                <Select
+                  mt={1}
+                  value={encryption}
+                  onChange={(e) => setEncryption(e.target.value)}
+                >
+                  <option value="none">不加密</option>
+                  <option value="passphrase">口令</option>
+                  <option value="recipient">接收者公钥</option>
+                </Select>
+                {encryption === 'passphrase' && (
+                  <Input
+                    mt={1}
+                    type="password"
+                    placeholder="接收者使用此口令解密"
+                    value={passphrase}
+                    onChange={(e) => setPassphrase(e.target.value)}
+                  />
+                )}
+                {encryption === 'recipient' && (
+                  <Input
+                    mt={1}
+                    type="text"
+                    placeholder="接收者的 X25519 公钥（64 位十六进制）"
+                    value={recipientKey}
+                    onChange={(e) => setRecipientKey(e.target.value)}
+                  />
+                )}
+              </div>
+              {encryption === 'none' && (
+                <div>
+                  压缩
+                  <Select
+                    mt={1}
+                    value={compression}
+                    onChange={(e) => setCompression(e.target.value)}
+                  >
+                    <option value="none">不压缩</option>
+                    <option value="zstd">zstd</option>
+                    <option value="lz4">lz4（更快）</option>
+                  </Select>
+                  <Checkbox
+                    mt={2}
+                    isChecked={delta}
+                    onChange={(e) => setDelta(e.target.checked)}
+                  >
+                    增量上传（仅发送与服务端同名文件不同的分块）
+                  </Checkbox>
+                </div>
+              )}
+              <div>
+                分块大小
+                <Select
+                  mt={1}
+                  value={effectiveBlockSize}
+                  onChange={(e) => setBlockSize(Number(e.target.value))}
+                >
+                  {[
+                    256 * 1024,
+                    1024 * 1024,
+                    4 * 1024 * 1024,
+                    16 * 1024 * 1024,
+                    64 * 1024 * 1024,
+                  ]
+                    .filter((size) => partitioned || size <= MAX_BLOCK_SIZE)
+                    .map((size) => (
+                      <option key={size} value={size}>
+                        {filesize(size, { standard: 'jedec' })}
+                      </option>
+                    ))}
+                </Select>
+              </div>
+              <div>
+                纠删码
+                <Select
+                  mt={1}
+                  value={codec}
+                  onChange={(e) => setCodec(e.target.value)}
+                >
+                  <option value="raptorq">RaptorQ</option>
+                  <option value="reed-solomon">Reed-Solomon（适合小分块）</option>
+                  <option value="plain">无纠删码（丢包重传）</option>
+                </Select>
+              </div>
+              {codec === 'raptorq' && (
+                <div>
+                  RaptorQ 源块划分
+                  <Select
+                    mt={1}
+                    value={sourceBlocks}
+                    onChange={(e) => setSourceBlocks(Number(e.target.value))}
+                  >
+                    <option value={1}>不划分</option>
+                    <option value={0}>按分块长度自动划分</option>
+                    <option value={4}>4 个源块</option>
+                    <option value={16}>16 个源块</option>
+                  </Select>
+                </div>
+              )}
+              <div>
+                交织深度
+                <Select
+                  mt={1}
+                  value={interleaveDepth}
+                  onChange={(e) => setInterleaveDepth(Number(e.target.value))}
+                >
+                  {[1, 2, 4, 8, 16].map((depth) => (
+                    <option key={depth} value={depth}>
+                      {depth === 1 ? '不交织' : `${depth} 个分块`}
+                    </option>
+                  ))}
+                </Select>
+              </div>
               <div>
                 包速率限制（PPS）
                 <Slider
@@ -1,9 +1,10 @@
 export interface Task {
   filename: string
-  fileSize: number
+  /** Null while a streamed upload hasn't reached the end of the stream. */
+  fileSize: number | null
   pps: number
   uuid: string
-  blockCount: number
+  blockCount: number | null
   remainBlockCount: number
   status: 'send' | 'done'
 }
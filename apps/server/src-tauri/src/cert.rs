@@ -0,0 +1,231 @@
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use rust_common::tls::fingerprint;
+use rustls::{
+  server::{ClientHello, ResolvesServerCert},
+  sign::{any_supported_type, CertifiedKey},
+};
+use tokio::fs;
+
+/// Days the previous self-signed certificate is still served after a rotation by default.
+pub const DEFAULT_ROTATION_DAYS: u32 = 7;
+
+/// Options of a generated self-signed certificate.
+pub struct CertOptions {
+  /// Subject alternative names. IP addresses become IP SANs, anything else DNS names.
+  pub names: Vec<String>,
+  /// `ecdsa-p256`, `ecdsa-p384` or `ed25519`.
+  pub key_algorithm: String,
+  /// Valid practically forever if unset.
+  pub validity_days: Option<u32>,
+  /// Days the previous certificate is still served, so clients can pin the new certificate or
+  /// fingerprint next to the old one before it takes effect. 0 switches right away.
+  pub rotation_days: u32,
+}
+
+impl Default for CertOptions {
+  fn default() -> Self {
+    Self {
+      names: vec!["qft-server".into()],
+      key_algorithm: "ecdsa-p256".into(),
+      validity_days: None,
+      rotation_days: DEFAULT_ROTATION_DAYS,
+    }
+  }
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertInfo {
+  pub fingerprint: String,
+  pub previous_fingerprint: Option<String>,
+  /// Unix time the previous certificate is served until.
+  pub previous_until: Option<u64>,
+}
+
+/// The self-signed certificate in `cert/`, and the previous one in `cert/previous/` during a
+/// rotation window.
+struct CertFiles {
+  current: (Vec<u8>, Vec<u8>),
+  previous: Option<(Vec<u8>, Vec<u8>, SystemTime)>,
+}
+
+fn previous_path(cert_path: &Path) -> PathBuf {
+  cert_path.join("previous")
+}
+
+async fn read_cert_files(cert_path: &Path) -> Result<CertFiles> {
+  let current = (
+    fs::read(cert_path.join("cert.der")).await?,
+    fs::read(cert_path.join("key.der")).await?,
+  );
+
+  let previous_path = previous_path(cert_path);
+  let previous = if fs::try_exists(previous_path.join("until")).await? {
+    let until: u64 = fs::read_to_string(previous_path.join("until"))
+      .await?
+      .trim()
+      .parse()
+      .context("Invalid rotation end time")?;
+    let until = UNIX_EPOCH + Duration::from_secs(until);
+    // An ended rotation window is ignored
+    (SystemTime::now() < until).then_some(until)
+  } else {
+    None
+  };
+  let previous = match previous {
+    Some(until) => Some((
+      fs::read(previous_path.join("cert.der")).await?,
+      fs::read(previous_path.join("key.der")).await?,
+      until,
+    )),
+    None => None,
+  };
+
+  Ok(CertFiles { current, previous })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs()
+}
+
+pub async fn cert_info(cert_path: &Path) -> Result<CertInfo> {
+  let files = read_cert_files(cert_path).await?;
+  Ok(CertInfo {
+    fingerprint: fingerprint(&files.current.0),
+    previous_fingerprint: files.previous.as_ref().map(|x| fingerprint(&x.0)),
+    previous_until: files.previous.as_ref().map(|x| unix_secs(x.2)),
+  })
+}
+
+/// Generate a self-signed certificate. The certificate served so far is kept for the rotation
+/// window instead of being overwritten.
+pub async fn generate_cert(cert_path: &Path, options: CertOptions) -> Result<CertInfo> {
+  let mut params = rcgen::CertificateParams::new(options.names);
+  params.alg = match options.key_algorithm.as_str() {
+    "ecdsa-p256" => &rcgen::PKCS_ECDSA_P256_SHA256,
+    "ecdsa-p384" => &rcgen::PKCS_ECDSA_P384_SHA384,
+    "ed25519" => &rcgen::PKCS_ED25519,
+    x => return Err(anyhow!("Unsupported key algorithm {}", x)),
+  };
+  if let Some(validity_days) = options.validity_days {
+    params.not_before = time::OffsetDateTime::now_utc();
+    params.not_after = params.not_before + time::Duration::days(validity_days as i64);
+  }
+  let gen_cert = rcgen::Certificate::from_params(params)?;
+  let cert = gen_cert.serialize_der()?;
+  let key = gen_cert.serialize_private_key_der();
+
+  // Keep serving whichever certificate clients currently see
+  let previous_path = previous_path(cert_path);
+  let served = match read_cert_files(cert_path).await {
+    Ok(CertFiles {
+      previous: Some((cert, key, _)),
+      ..
+    }) => Some((cert, key)),
+    Ok(CertFiles { current, .. }) => Some(current),
+    Err(_) => None,
+  };
+  if fs::try_exists(&previous_path).await? {
+    fs::remove_dir_all(&previous_path).await?;
+  }
+  if let Some((served_cert, served_key)) = served.filter(|_| options.rotation_days > 0) {
+    let until = SystemTime::now() + Duration::from_secs(options.rotation_days as u64 * 86400);
+    fs::create_dir_all(&previous_path).await?;
+    fs::write(previous_path.join("cert.der"), served_cert).await?;
+    fs::write(previous_path.join("key.der"), served_key).await?;
+    fs::write(previous_path.join("until"), unix_secs(until).to_string()).await?;
+  }
+
+  fs::create_dir_all(cert_path).await?;
+  fs::write(cert_path.join("cert.der"), &cert).await?;
+  fs::write(cert_path.join("key.der"), &key).await?;
+
+  cert_info(cert_path).await
+}
+
+/// Serves the previous self-signed certificate until its rotation window ends, then the current
+/// one. Clients trusting both meanwhile connect before and after the switch.
+pub struct RotatingCert {
+  current: Arc<CertifiedKey>,
+  previous: Option<(Arc<CertifiedKey>, SystemTime)>,
+}
+
+fn certified_key(cert: Vec<u8>, key: Vec<u8>) -> Result<Arc<CertifiedKey>> {
+  let key = any_supported_type(&rustls::PrivateKey(key))?;
+  Ok(Arc::new(CertifiedKey::new(
+    vec![rustls::Certificate(cert)],
+    key,
+  )))
+}
+
+impl RotatingCert {
+  pub async fn load(cert_path: &Path) -> Result<Self> {
+    let files = read_cert_files(cert_path).await?;
+    let previous = match files.previous {
+      Some((cert, key, until)) => Some((certified_key(cert, key)?, until)),
+      None => None,
+    };
+    Ok(Self {
+      current: certified_key(files.current.0, files.current.1)?,
+      previous,
+    })
+  }
+
+  fn served(&self, now: SystemTime) -> Arc<CertifiedKey> {
+    match &self.previous {
+      Some((previous, until)) if now < *until => previous.clone(),
+      _ => self.current.clone(),
+    }
+  }
+}
+
+impl ResolvesServerCert for RotatingCert {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    Some(self.served(SystemTime::now()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn clients_pinning_both_certs_connect_through_rotation() {
+    let cert_path = std::env::temp_dir().join(format!("qft-cert-{}", std::process::id()));
+    generate_cert(&cert_path, CertOptions::default())
+      .await
+      .unwrap();
+    let old = fs::read(cert_path.join("cert.der")).await.unwrap();
+    let info = generate_cert(&cert_path, CertOptions::default())
+      .await
+      .unwrap();
+    let new = fs::read(cert_path.join("cert.der")).await.unwrap();
+    assert_eq!(info.previous_fingerprint, Some(fingerprint(&old)));
+    assert_eq!(info.fingerprint, fingerprint(&new));
+
+    let certs = RotatingCert::load(&cert_path).await.unwrap();
+    let during = SystemTime::now();
+    let after = during + Duration::from_secs(DEFAULT_ROTATION_DAYS as u64 * 86400 + 1);
+    let served = |now| certs.served(now).cert[0].0.clone();
+
+    // Clients which only know the old certificate keep working until the window ends
+    assert_eq!(served(during), old);
+    assert_eq!(served(after), new);
+
+    // Clients given the new certificate during the window pin it next to the old one
+    let pinned = [old, new];
+    assert!(pinned.contains(&served(during)));
+    assert!(pinned.contains(&served(after)));
+
+    fs::remove_dir_all(cert_path).await.unwrap();
+  }
+}
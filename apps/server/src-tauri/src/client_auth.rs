@@ -0,0 +1,107 @@
+use std::{sync::Arc, time::SystemTime};
+
+use anyhow::{Context, Result};
+use rust_common::tls::{fingerprint, fingerprint_matches};
+use rustls::{
+  server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier},
+  Certificate, DistinguishedName, RootCertStore,
+};
+use tauri::AppHandle;
+use tokio::fs;
+
+use crate::config::{ClientAuth, TrustedClient};
+
+/// Trusts client certificates issued by the configured CA or listed by fingerprint.
+struct ClientVerifier {
+  ca: Option<AllowAnyAuthenticatedClient>,
+  fingerprints: Vec<TrustedClient>,
+  required: bool,
+}
+
+fn find_trusted<'a>(
+  fingerprints: &'a [TrustedClient],
+  cert: &Certificate,
+) -> Option<&'a TrustedClient> {
+  let cert_fingerprint = fingerprint(&cert.0);
+  fingerprints
+    .iter()
+    .find(|x| fingerprint_matches(&x.fingerprint, &cert_fingerprint))
+}
+
+impl ClientCertVerifier for ClientVerifier {
+  fn client_auth_mandatory(&self) -> bool {
+    self.required
+  }
+
+  fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+    match &self.ca {
+      Some(ca) => ca.client_auth_root_subjects(),
+      None => &[],
+    }
+  }
+
+  fn verify_client_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    now: SystemTime,
+  ) -> Result<ClientCertVerified, rustls::Error> {
+    if find_trusted(&self.fingerprints, end_entity).is_some() {
+      return Ok(ClientCertVerified::assertion());
+    }
+    match &self.ca {
+      Some(ca) => ca.verify_client_cert(end_entity, intermediates, now),
+      None => Err(rustls::Error::InvalidCertificate(
+        rustls::CertificateError::UnknownIssuer,
+      )),
+    }
+  }
+}
+
+pub async fn client_verifier(
+  app_handle: &AppHandle,
+  client_auth: &ClientAuth,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+  let ca = match &client_auth.ca {
+    Some(path) => {
+      let path = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .unwrap()
+        .join(path);
+      let cert = fs::read(&path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+      let mut roots = RootCertStore::empty();
+      roots.add(&Certificate(cert))?;
+      Some(AllowAnyAuthenticatedClient::new(roots))
+    }
+    None => None,
+  };
+
+  Ok(Arc::new(ClientVerifier {
+    ca,
+    fingerprints: client_auth.fingerprints.clone(),
+    required: client_auth.required,
+  }))
+}
+
+/// Identity of an authenticated client: the configured name of a trusted fingerprint, or else the
+/// common name of a CA issued certificate.
+pub fn client_identity(conn: &quinn::Connection, client_auth: &ClientAuth) -> Option<String> {
+  let certs = conn.peer_identity()?.downcast::<Vec<Certificate>>().ok()?;
+  let cert = certs.first()?;
+
+  if let Some(trusted) = find_trusted(&client_auth.fingerprints, cert) {
+    return Some(trusted.name.clone());
+  }
+
+  let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+  let common_name = parsed
+    .subject()
+    .iter_common_name()
+    .next()
+    .and_then(|x| x.as_str().ok())
+    .map(String::from);
+  Some(common_name.unwrap_or_else(|| fingerprint(&cert.0)))
+}
@@ -1,24 +1,40 @@
 use std::path::PathBuf;
 
 use tauri::AppHandle;
-use tokio::fs;
 
-use crate::server::server_thread;
+use crate::{
+  cert::{cert_info, generate_cert, CertInfo, CertOptions},
+  server::server_thread,
+};
 
+/// Generate a self-signed certificate, keeping the previous one for a rotation window.
 #[tauri::command]
-pub async fn gen_cert(app_handle: AppHandle) {
+pub async fn gen_cert(
+  app_handle: AppHandle,
+  names: Option<Vec<String>>,
+  key_algorithm: Option<String>,
+  validity_days: Option<u32>,
+  rotation_days: Option<u32>,
+) -> Result<CertInfo, String> {
   let base_path = PathBuf::from(app_handle.path_resolver().app_data_dir().unwrap()).join("cert");
-  let cert_path = base_path.join("cert.der");
-  let key_path = base_path.join("key.der");
 
-  let gen_cert = rcgen::generate_simple_self_signed(vec!["qft-server".into()]).unwrap();
+  let default = CertOptions::default();
+  let options = CertOptions {
+    names: names.filter(|x| !x.is_empty()).unwrap_or(default.names),
+    key_algorithm: key_algorithm.unwrap_or(default.key_algorithm),
+    validity_days,
+    rotation_days: rotation_days.unwrap_or(default.rotation_days),
+  };
 
-  let cert = gen_cert.serialize_der().unwrap();
-  let key = gen_cert.serialize_private_key_der();
+  generate_cert(&base_path, options)
+    .await
+    .map_err(|e| e.to_string())
+}
 
-  fs::create_dir_all(base_path).await.unwrap();
-  fs::write(&cert_path, &cert).await.unwrap();
-  fs::write(&key_path, &key).await.unwrap();
+#[tauri::command]
+pub async fn get_cert_info(app_handle: AppHandle) -> Result<CertInfo, String> {
+  let base_path = PathBuf::from(app_handle.path_resolver().app_data_dir().unwrap()).join("cert");
+  cert_info(&base_path).await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
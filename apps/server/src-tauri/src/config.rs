@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use rust_common::error::RequestError;
+use tauri::AppHandle;
+use tokio::fs;
+
+/// Server settings, read from `config.json` in the app data directory. Missing fields fall back
+/// to their defaults.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+  /// Bytes of received packets buffered for the incomplete blocks of one task.
+  pub max_task_buffer: u64,
+  /// Bytes of received packets buffered for all tasks.
+  pub max_total_buffer: u64,
+  /// Tokens accepted in upload requests. Uploads need no token if empty.
+  pub tokens: Vec<AccessToken>,
+  /// Client certificate authentication. Clients are anonymous if unset.
+  pub client_auth: Option<ClientAuth>,
+  /// PEM certificate chain and key, e.g. issued by an internal CA. The self-signed
+  /// `cert/cert.der` and `cert/key.der` are used if unset.
+  pub certificate: Option<CertificateFiles>,
+  /// Where completed transfers go.
+  pub sink: SinkConfig,
+  /// Commands run in order once a transfer was handed to the sink.
+  pub hooks: Vec<Hook>,
+  /// Directories uploads go to instead of the sink, by the first matching route. Uploads no
+  /// route matches are rejected, unless there are no routes.
+  pub routes: Vec<Route>,
+  /// Upload quotas by the first matching rule. Uploads no quota matches are unlimited.
+  pub quotas: Vec<Quota>,
+  /// Bytes kept free in the temp and receive directories when accepting uploads.
+  pub min_free_space: u64,
+  pub limits: Limits,
+}
+
+/// Limits of what the server accepts. Unset limits are unlimited.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Limits {
+  /// Bytes of a single file.
+  pub max_file_size: Option<u64>,
+  /// UTF-8 bytes of a filename, at most 1024.
+  pub max_filename_length: usize,
+  /// Uploads in flight on one connection.
+  pub max_tasks_per_connection: Option<usize>,
+  /// Uploads in flight on the server.
+  pub max_tasks: Option<usize>,
+  /// Client connections at once. Further connections are refused during the handshake.
+  pub max_connections: Option<u32>,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self {
+      max_file_size: None,
+      max_filename_length: 255,
+      max_tasks_per_connection: None,
+      max_tasks: None,
+      max_connections: None,
+    }
+  }
+}
+
+/// Bytes a client may upload in total, counted in `usage.json` in the app data directory.
+/// Patterns may use `*` for any characters and `?` for one. Every pattern set must match, so a
+/// quota without patterns applies to every client.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Quota {
+  /// Client identity. Anonymous clients don't match.
+  pub client: Option<String>,
+  /// Name of the access token. Uploads without a token don't match.
+  pub token: Option<String>,
+  pub bytes: u64,
+}
+
+/// Patterns may use `*` for any characters and `?` for one. Every pattern set must match.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Route {
+  /// Client identity. Anonymous clients don't match.
+  pub client: Option<String>,
+  /// Destination tag of the upload. Uploads without a tag don't match.
+  pub tag: Option<String>,
+  pub filename: Option<String>,
+  /// Relative to the app data directory.
+  pub dir: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Hook {
+  /// Shown in the log and the task event.
+  pub name: String,
+  pub program: String,
+  #[serde(default)]
+  pub args: Vec<String>,
+  /// Seconds before the command is killed.
+  #[serde(default = "default_hook_timeout")]
+  pub timeout: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+  60
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SinkConfig {
+  /// Files in a directory, relative to the app data directory.
+  Dir {
+    #[serde(default = "default_sink_dir")]
+    path: String,
+  },
+  /// Framed files on stdout, for piping the server into another program.
+  Stdout,
+  /// A command run with the path of each file.
+  Command {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+  },
+}
+
+fn default_sink_dir() -> String {
+  "recv".to_string()
+}
+
+impl Default for SinkConfig {
+  fn default() -> Self {
+    Self::Dir {
+      path: default_sink_dir(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct CertificateFiles {
+  /// Relative to the app data directory, starting with the server certificate.
+  pub chain: String,
+  pub key: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AccessToken {
+  /// Recorded on each task uploaded with the token.
+  pub name: String,
+  pub token: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientAuth {
+  /// DER encoded CA certificate, relative to the app data directory. Clients it issued are
+  /// identified by the common name of their certificate.
+  pub ca: Option<String>,
+  /// Trusted client certificates by SHA-256 fingerprint.
+  #[serde(default)]
+  pub fingerprints: Vec<TrustedClient>,
+  /// Refuse connections without a client certificate.
+  #[serde(default = "default_true")]
+  pub required: bool,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TrustedClient {
+  /// Identity of the client.
+  pub name: String,
+  pub fingerprint: String,
+}
+
+fn default_true() -> bool {
+  true
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      max_task_buffer: 256 * 1024 * 1024,
+      max_total_buffer: 1024 * 1024 * 1024,
+      tokens: vec![],
+      client_auth: None,
+      certificate: None,
+      sink: SinkConfig::default(),
+      hooks: vec![],
+      routes: vec![],
+      quotas: vec![],
+      min_free_space: 0,
+      limits: Limits::default(),
+    }
+  }
+}
+
+impl Config {
+  /// Find the access token. Compares in constant time, so the timing doesn't leak how much of a
+  /// guess is right.
+  pub fn find_token(&self, token: &str) -> Option<&AccessToken> {
+    let mut found = None;
+    for access_token in &self.tokens {
+      if constant_time_eq(access_token.token.as_bytes(), token.as_bytes()) {
+        found = Some(access_token);
+      }
+    }
+    found
+  }
+
+  /// Name of the access token a request was made with, or `None` if uploads need no token.
+  pub fn authorize(&self, token: Vec<u8>) -> Result<Option<String>, RequestError> {
+    if self.tokens.is_empty() {
+      return Ok(None);
+    }
+    let token = String::from_utf8(token).unwrap_or_default();
+    match self.find_token(&token) {
+      Some(access_token) => Ok(Some(access_token.name.clone())),
+      None => Err(RequestError::Unauthorized),
+    }
+  }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn load_config(app_handle: &AppHandle) -> Result<Config> {
+  let path = app_handle
+    .path_resolver()
+    .app_data_dir()
+    .unwrap()
+    .join("config.json");
+  if !fs::try_exists(&path).await? {
+    return Ok(Config::default());
+  }
+
+  let config = serde_json::from_slice(&fs::read(&path).await?)
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+  Ok(config)
+}
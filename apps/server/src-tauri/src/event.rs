@@ -1,7 +1,6 @@
-use rust_common::erasure::BLOCK_SIZE;
 use tauri::{AppHandle, Manager};
 
-use crate::server::Task;
+use crate::{hook::HookResult, server::Task};
 
 #[derive(Clone, serde::Serialize)]
 pub enum TaskStatus {
@@ -11,17 +10,25 @@ pub enum TaskStatus {
   Merge,
   #[serde(rename = "done")]
   Done,
+  /// Merging the file or handing it to the sink failed.
+  #[serde(rename = "failed")]
+  Failed,
 }
 
 #[derive(Clone, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TaskEvent {
   pub filename: String,
-  pub file_size: u64,
+  /// None while a streamed upload hasn't announced its size.
+  pub file_size: Option<u64>,
   pub uuid: String,
-  pub block_count: u32,
+  pub block_count: Option<u32>,
   pub done_block_count: u32,
   pub status: TaskStatus,
+  pub token_name: Option<String>,
+  pub client_identity: Option<String>,
+  pub encrypted: bool,
+  pub hooks: Vec<HookResult>,
 }
 
 pub fn emit_task_event(app_handle: &AppHandle, uuid: u128, task: &Task, status: TaskStatus) {
@@ -30,11 +37,17 @@ pub fn emit_task_event(app_handle: &AppHandle, uuid: u128, task: &Task, status:
       "task",
       TaskEvent {
         filename: task.filename.clone(),
-        file_size: task.file_size,
+        file_size: task.is_size_known().then_some(task.file_size),
         uuid: uuid.to_string(),
-        block_count: (task.file_size as f32 / BLOCK_SIZE as f32).ceil() as u32,
+        block_count: task
+          .is_size_known()
+          .then(|| task.params.block_count(task.file_size)),
         done_block_count: task.rebuilt_blocks.len() as u32,
         status,
+        token_name: task.token_name.clone(),
+        client_identity: task.client_identity.clone(),
+        encrypted: task.params.encrypted,
+        hooks: task.hook_results.clone(),
       },
     )
     .unwrap();
@@ -0,0 +1,90 @@
+use std::{process::Stdio, time::Duration};
+
+use tokio::{process::Command, time};
+
+use crate::{config::Hook, log::log};
+
+/// Bytes of hook output kept for the task event. Longer output keeps its end.
+const MAX_HOOK_OUTPUT: usize = 4096;
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookResult {
+  pub name: String,
+  pub success: bool,
+  /// None if the hook couldn't start, timed out or was killed by a signal.
+  pub exit_code: Option<i32>,
+  /// Stdout followed by stderr, or why the hook failed to run.
+  pub output: String,
+}
+
+fn truncate_output(output: &[u8]) -> String {
+  let start = output.len().saturating_sub(MAX_HOOK_OUTPUT);
+  String::from_utf8_lossy(&output[start..]).to_string()
+}
+
+async fn run_hook(hook: &Hook, env: &[(&'static str, String)]) -> HookResult {
+  let child = Command::new(&hook.program)
+    .args(&hook.args)
+    .envs(env.iter().map(|(key, value)| (key, value)))
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .spawn();
+  let child = match child {
+    Ok(child) => child,
+    Err(e) => {
+      return HookResult {
+        name: hook.name.clone(),
+        success: false,
+        exit_code: None,
+        output: format!("failed to start {}: {}", hook.program, e),
+      }
+    }
+  };
+
+  let output = time::timeout(Duration::from_secs(hook.timeout), child.wait_with_output()).await;
+  match output {
+    Ok(Ok(output)) => HookResult {
+      name: hook.name.clone(),
+      success: output.status.success(),
+      exit_code: output.status.code(),
+      output: truncate_output(&[output.stdout, output.stderr].concat()),
+    },
+    Ok(Err(e)) => HookResult {
+      name: hook.name.clone(),
+      success: false,
+      exit_code: None,
+      output: e.to_string(),
+    },
+    Err(_) => HookResult {
+      name: hook.name.clone(),
+      success: false,
+      exit_code: None,
+      output: format!("killed after {} seconds", hook.timeout),
+    },
+  }
+}
+
+/// Run the post-receive hooks in order with the metadata of the received file in `env`. A
+/// failing hook doesn't stop the ones after it.
+pub async fn run_hooks(hooks: &[Hook], env: &[(&'static str, String)]) -> Vec<HookResult> {
+  let mut results = vec![];
+  for hook in hooks {
+    let result = run_hook(hook, env).await;
+    log!(
+      "Hook {} {} (exit code {:?}): {}",
+      result.name,
+      if result.success {
+        "succeeded"
+      } else {
+        "failed"
+      },
+      result.exit_code,
+      result.output.trim_end()
+    );
+    results.push(result);
+  }
+  results
+}
@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set while the stdout sink writes received files to stdout, which log lines must not mix into.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_to_stderr(to_stderr: bool) {
+  LOG_TO_STDERR.store(to_stderr, Ordering::Relaxed);
+}
+
+pub fn log_to_stderr() -> bool {
+  LOG_TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// Print a log line to stdout, or to stderr while stdout carries received files.
+macro_rules! log {
+  ($($arg:tt)*) => {
+    if $crate::log::log_to_stderr() {
+      eprintln!($($arg)*);
+    } else {
+      println!($($arg)*);
+    }
+  };
+}
+
+pub(crate) use log;
@@ -1,15 +1,27 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 // #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod cert;
+mod client_auth;
 mod commands;
+mod config;
 mod event;
+mod hook;
+mod log;
+mod route;
 mod server;
+mod sink;
+mod space;
 
-use crate::commands::{gen_cert, start_server};
+use crate::commands::{gen_cert, get_cert_info, start_server};
 
 fn main() {
   tauri::Builder::default()
-    .invoke_handler(tauri::generate_handler![gen_cert, start_server])
+    .invoke_handler(tauri::generate_handler![
+      gen_cert,
+      get_cert_info,
+      start_server
+    ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
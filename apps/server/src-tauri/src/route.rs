@@ -0,0 +1,147 @@
+use std::path::Path;
+
+use rust_common::error::RequestError;
+
+use crate::config::{Config, Quota, Route};
+
+/// Whether `text` matches a pattern where `*` stands for any characters and `?` for one.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let text: Vec<char> = text.chars().collect();
+  let (mut p, mut t) = (0, 0);
+  // Position after the last `*` and the text position it was tried at
+  let mut backtrack = None;
+
+  while t < text.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+      p += 1;
+      t += 1;
+    } else if p < pattern.len() && pattern[p] == '*' {
+      p += 1;
+      backtrack = Some((p, t));
+    } else if let Some((star_p, star_t)) = backtrack {
+      // Let the `*` take one more character
+      p = star_p;
+      t = star_t + 1;
+      backtrack = Some((star_p, star_t + 1));
+    } else {
+      return false;
+    }
+  }
+  pattern[p..].iter().all(|x| *x == '*')
+}
+
+fn matches(pattern: &Option<String>, value: Option<&str>) -> bool {
+  match (pattern, value) {
+    (None, _) => true,
+    (Some(pattern), Some(value)) => wildcard_match(pattern, value),
+    (Some(_), None) => false,
+  }
+}
+
+impl Route {
+  pub fn matches(&self, identity: Option<&str>, tag: Option<&str>, filename: &str) -> bool {
+    matches(&self.client, identity)
+      && matches(&self.tag, tag)
+      && matches(&self.filename, Some(filename))
+  }
+}
+
+impl Quota {
+  pub fn matches(&self, identity: Option<&str>, token_name: Option<&str>) -> bool {
+    matches(&self.client, identity) && matches(&self.token, token_name)
+  }
+}
+
+/// Directory of the first route matching the upload, or `None` to use the sink if no routes are
+/// configured. Uploads no route matches are rejected.
+pub fn route_upload(
+  config: &Config,
+  identity: Option<&str>,
+  tag: Option<&str>,
+  filename: &str,
+) -> Result<Option<String>, RequestError> {
+  if config.routes.is_empty() {
+    return Ok(None);
+  }
+  config
+    .routes
+    .iter()
+    .find(|x| x.matches(identity, tag, filename))
+    .map(|x| Some(x.dir.clone()))
+    .ok_or(RequestError::Forbidden)
+}
+
+/// Only plain file names are accepted, so uploads can't escape their directory. Control
+/// characters are rejected too, since the name ends up in the stdout sink's header line.
+pub fn is_plain_filename(filename: &str) -> bool {
+  !filename.chars().any(char::is_control)
+    && Path::new(filename)
+      .file_name()
+      .is_some_and(|x| x == filename)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn star_matches_any_characters() {
+    assert!(wildcard_match("*", ""));
+    assert!(wildcard_match("*", "anything"));
+    assert!(wildcard_match("*.log", "server.log"));
+    assert!(wildcard_match("a*b*c", "aXbYbZc"));
+    assert!(wildcard_match("**", "abc"));
+    assert!(!wildcard_match("*.log", "server.txt"));
+  }
+
+  #[test]
+  fn question_mark_matches_one_character() {
+    assert!(wildcard_match("?", "a"));
+    assert!(wildcard_match("file-??.txt", "file-01.txt"));
+    assert!(!wildcard_match("?", ""));
+    assert!(!wildcard_match("?", "ab"));
+    assert!(!wildcard_match("file-??.txt", "file-1.txt"));
+  }
+
+  #[test]
+  fn patterns_match_the_whole_text() {
+    assert!(wildcard_match("abc", "abc"));
+    assert!(!wildcard_match("abc", "abcd"));
+    assert!(!wildcard_match("abc", "xabc"));
+    assert!(!wildcard_match("*.log", "server.log.1"));
+    assert!(!wildcard_match("server*", "my-server"));
+  }
+
+  #[test]
+  fn empty_pattern_only_matches_empty_text() {
+    assert!(wildcard_match("", ""));
+    assert!(!wildcard_match("", "a"));
+  }
+
+  #[test]
+  fn plain_filenames_are_accepted() {
+    assert!(is_plain_filename("report.pdf"));
+    assert!(is_plain_filename(".hidden"));
+    assert!(is_plain_filename("文件 1.txt"));
+  }
+
+  #[test]
+  fn paths_are_rejected() {
+    assert!(!is_plain_filename(""));
+    assert!(!is_plain_filename("."));
+    assert!(!is_plain_filename(".."));
+    assert!(!is_plain_filename("../secret"));
+    assert!(!is_plain_filename("dir/file"));
+    assert!(!is_plain_filename("/etc/passwd"));
+    assert!(!is_plain_filename("file/"));
+  }
+
+  #[test]
+  fn control_characters_are_rejected() {
+    assert!(!is_plain_filename("a\nQFT 0 b"));
+    assert!(!is_plain_filename("a\rb"));
+    assert!(!is_plain_filename("a\0b"));
+    assert!(!is_plain_filename("a\u{7f}b"));
+  }
+}
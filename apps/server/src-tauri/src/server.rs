@@ -1,52 +1,274 @@
 use std::{
-  collections::{HashMap, HashSet},
+  collections::{HashMap, HashSet, VecDeque},
   env,
   io::Cursor,
   net::SocketAddr,
-  path::PathBuf,
+  path::{Path, PathBuf},
   str::FromStr,
-  sync::Arc,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
 };
 
 use anyhow::{anyhow, Context, Result};
 use once_cell::sync::Lazy;
 use rust_common::{
-  erasure::{decode_block, BLOCK_SIZE, DATA_PACKET_COUNT_PER_BLOCK},
+  codec::PAYLOAD_ID_LENGTH,
+  crypto::{EncryptionHeader, ENCRYPTED_EXTENSION},
+  delta::{block_hashes, open_base, reuse_block, ReusedBlock},
+  erasure::decode_block,
+  error::RequestError,
   flags::{
-    FLAG_FILE_DECODE_ERROR, FLAG_FILE_DECODE_OK, FLAG_HEARTBEAT, FLAG_REQUEST_ID,
-    FLAG_UPLOAD_COMPLETE, FLAG_UPLOAD_PACKET,
+    FLAG_BLOCK_HASHES, FLAG_ERROR, FLAG_FILE_DECODE_ERROR, FLAG_FILE_DECODE_OK, FLAG_FILE_SIZE,
+    FLAG_HEARTBEAT, FLAG_OK, FLAG_REQUEST_ID, FLAG_REUSE_BLOCKS, FLAG_UPDATE_PARAMS,
+    FLAG_UPLOAD_COMPLETE, FLAG_UPLOAD_PACKET, UNKNOWN_FILE_SIZE,
   },
+  hash::file_hash,
+  params::{TransferParams, MAX_PARTITIONED_BLOCK_SIZE, MIN_BLOCK_SIZE},
+  pipeline::DecodePool,
+  tls::{pem_certs, pem_private_key},
 };
 use tauri::AppHandle;
 use tokio::{
   fs::{self, File},
   io::{self, AsyncReadExt, AsyncWriteExt},
-  sync::Mutex,
+  sync::{Mutex, MutexGuard, Notify, OwnedMutexGuard, RwLock},
 };
 use uuid::Uuid;
 
-use crate::event::{emit_task_event, TaskStatus};
+use crate::{
+  cert::RotatingCert,
+  client_auth::{client_identity, client_verifier},
+  config::{load_config, CertificateFiles, Config, Limits, SinkConfig},
+  event::{emit_task_event, TaskStatus},
+  hook::{run_hooks, HookResult},
+  log::{log, set_log_to_stderr},
+  route::{is_plain_filename, route_upload},
+  sink::{create_sink, sink_dir, DirSink, ReceivedFile},
+  space::{record_usage, reserve, Account, Reservation},
+};
+
+/// A client connection, shared by its datagram and stream handlers.
+pub struct Peer {
+  /// Unique for the lifetime of the server, unlike quinn's `stable_id` which may be reused.
+  pub id: u64,
+  pub remote_addr: SocketAddr,
+  /// Identity of the client certificate, if client authentication is configured.
+  pub identity: Option<String>,
+  /// Datagrams for tasks which don't exist or belong to another connection.
+  pub rejected_datagrams: AtomicU64,
+}
+
+static NEXT_PEER_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct Task {
+  /// Name of the access token the upload was requested with.
+  pub token_name: Option<String>,
+  pub client_identity: Option<String>,
   pub filename: String,
+  /// `UNKNOWN_FILE_SIZE` until a streamed upload announces its size.
   pub file_size: u64,
+  pub params: TransferParams,
+  /// Stored in front of the blocks of an end-to-end encrypted upload, which is only decrypted
+  /// by the recipient.
+  pub encryption_header: Option<Vec<u8>>,
+  /// Directory of the route the upload matched. The sink takes it if None.
+  pub route_dir: Option<String>,
   pub recv_blocks: HashMap<u32, HashSet<bytes::Bytes>>,
+  /// Encoded length of the blocks in `recv_blocks`, taken from their first packet.
+  pub encoded_lens: HashMap<u32, u64>,
+  /// Blocks in `recv_blocks`, oldest first.
+  pub recv_order: VecDeque<u32>,
+  /// Bytes of packets in `recv_blocks`.
+  pub buffered_bytes: u64,
   pub rebuilt_blocks: HashSet<u32>,
+  /// Blocks handed to the decode pool and not finished yet.
+  pub decoding_blocks: HashSet<u32>,
+  /// Notified whenever a block finishes decoding.
+  pub decoded: Arc<Notify>,
+  /// Post-receive hooks which ran after the merge.
+  pub hook_results: Vec<HookResult>,
+  /// Counts the task against the limits until it is stored or dropped.
+  pub reservation: Reservation,
+  /// Set once the task is removed from `TASKS`, so requests and packets which got hold of it
+  /// before are ignored.
+  pub closed: bool,
+}
+
+/// A task and the ID of the connection which requested it, the only one which may send packets
+/// for it. Kept outside the task, so it's checked without locking the task.
+pub type TaskEntry = (u64, Arc<Mutex<Task>>);
+
+/// Each task has its own lock, so uploads don't wait on each other. The map lock is only held
+/// to look up, add or remove a task.
+pub static TASKS: Lazy<RwLock<HashMap<u128, TaskEntry>>> =
+  Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Bytes of received packets buffered for all tasks.
+static BUFFERED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+static DECODE_POOL: Lazy<DecodePool> = Lazy::new(DecodePool::default);
+
+/// Look up a task of the peer. Tasks of other connections are treated as unknown.
+async fn get_task(uuid: u128, peer: &Peer) -> Option<Arc<Mutex<Task>>> {
+  let tasks = TASKS.read().await;
+  let (peer_id, task) = tasks.get(&uuid)?;
+  (*peer_id == peer.id).then_some(task.clone())
+}
+
+/// Lock a task of the peer, unless it is closed by the time the lock is acquired.
+async fn lock_task(uuid: u128, peer: &Peer) -> Option<OwnedMutexGuard<Task>> {
+  let task = get_task(uuid, peer).await?.lock_owned().await;
+  (!task.closed).then_some(task)
 }
 
-static TASKS: Lazy<Mutex<HashMap<u128, Task>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Lock a task once none of its blocks are in the decode pool.
+async fn lock_idle(task: &Mutex<Task>) -> MutexGuard<'_, Task> {
+  loop {
+    let guard = task.lock().await;
+    if guard.decoding_blocks.is_empty() {
+      return guard;
+    }
+    let decoded = guard.decoded.clone();
+    let notified = decoded.notified();
+    tokio::pin!(notified);
+    // Registered before unlocking, so a block finishing meanwhile isn't missed
+    notified.as_mut().enable();
+    drop(guard);
+    notified.await;
+  }
+}
+
+/// Give up the tasks of a closed connection. No other connection may finish them, so they would
+/// keep their buffered packets and rebuilt blocks forever.
+async fn drop_peer_tasks(peer_id: u64, tmp_base_path: &str) {
+  let tasks: Vec<(u128, Arc<Mutex<Task>>)> = {
+    let mut tasks = TASKS.write().await;
+    let uuids: Vec<u128> = tasks
+      .iter()
+      .filter(|(_, (owner, _))| *owner == peer_id)
+      .map(|(uuid, _)| *uuid)
+      .collect();
+    uuids
+      .into_iter()
+      .filter_map(|uuid| tasks.remove(&uuid).map(|(_, task)| (uuid, task)))
+      .collect()
+  };
+
+  for (uuid, task) in tasks {
+    let mut task = lock_idle(&task).await;
+    log!("Drop unfinished upload of {}", task.filename);
+    give_up_task(uuid, &mut task, Path::new(tmp_base_path)).await;
+  }
+}
+
+async fn remove_tmp_dir(tmp_path: &Path) {
+  match fs::remove_dir_all(tmp_path).await {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+      log!("Failed to remove {}: {}", tmp_path.display(), e)
+    }
+    _ => {}
+  }
+}
+
+/// Remove a task which won't be finished, and free everything it holds: its buffered packets,
+/// its rebuilt blocks and its reservation.
+async fn give_up_task(uuid: u128, task: &mut Task, tmp_base_path: &Path) {
+  task.closed = true;
+  task.drop_all_blocks();
+  task.reservation.release();
+  TASKS.write().await.remove(&uuid);
+  remove_tmp_dir(&tmp_base_path.join(uuid.to_string())).await;
+}
+
+impl Task {
+  pub fn is_size_known(&self) -> bool {
+    self.file_size != UNKNOWN_FILE_SIZE
+  }
+
+  fn buffer_packet(&mut self, block_id: u32, packet: bytes::Bytes) {
+    let len = packet.len() as u64;
+    let recv_map = self.recv_blocks.entry(block_id).or_insert_with(|| {
+      self.recv_order.push_back(block_id);
+      HashSet::new()
+    });
+    if recv_map.insert(packet) {
+      self.buffered_bytes += len;
+      BUFFERED_BYTES.fetch_add(len, Ordering::Relaxed);
+    }
+  }
+
+  /// Drop the buffered packets of a block. Unless it is rebuilt, it's reported missing when the
+  /// upload completes.
+  fn drop_block(&mut self, block_id: u32) {
+    if let Some(recv_map) = self.recv_blocks.remove(&block_id) {
+      let len: u64 = recv_map.iter().map(|x| x.len() as u64).sum();
+      self.buffered_bytes -= len;
+      BUFFERED_BYTES.fetch_sub(len, Ordering::Relaxed);
+      self.recv_order.retain(|x| *x != block_id);
+    }
+    self.encoded_lens.remove(&block_id);
+  }
+
+  fn rebuild_block(&mut self, block_id: u32) {
+    self.rebuilt_blocks.insert(block_id);
+    self.drop_block(block_id);
+    self.reservation.add_rebuilt(self.params.block_size);
+  }
+
+  fn drop_all_blocks(&mut self) {
+    while let Some(block_id) = self.recv_order.front() {
+      self.drop_block(*block_id);
+    }
+  }
+
+  /// Make room for `len` more buffered bytes by dropping the oldest partial blocks other than
+  /// `block_id`. Returns false if that isn't enough.
+  fn reserve(&mut self, block_id: u32, len: u64, config: &Config) -> bool {
+    while self.buffered_bytes + len > config.max_task_buffer
+      || BUFFERED_BYTES.load(Ordering::Relaxed) + len > config.max_total_buffer
+    {
+      let oldest = self
+        .recv_order
+        .iter()
+        .find(|x| **x != block_id && !self.decoding_blocks.contains(x));
+      let Some(oldest) = oldest.copied() else {
+        return false;
+      };
 
-pub async fn get_self_signed_cert(
+      log!(
+        "Buffer full. Drop partial block {} of {}",
+        oldest,
+        self.filename
+      );
+      self.drop_block(oldest);
+    }
+    true
+  }
+}
+
+/// Load the configured PEM certificate chain and key.
+pub async fn get_certificate_chain(
   app_handle: AppHandle,
-) -> Result<(rustls::Certificate, rustls::PrivateKey)> {
-  let base_path = PathBuf::from(app_handle.path_resolver().app_data_dir().unwrap()).join("cert");
-  let cert_path = base_path.join("cert.der");
-  let key_path = base_path.join("key.der");
+  files: &CertificateFiles,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+  let base_path = app_handle.path_resolver().app_data_dir().unwrap();
+  let chain_path = base_path.join(&files.chain);
+  let key_path = base_path.join(&files.key);
 
-  let cert = fs::read(&cert_path).await?;
-  let key = fs::read(&key_path).await?;
+  let chain = pem_certs(&fs::read(&chain_path).await?)
+    .with_context(|| format!("Failed to parse {}", chain_path.display()))?;
+  if chain.is_empty() {
+    return Err(anyhow!("No certificate in {}", chain_path.display()));
+  }
+  let key = pem_private_key(&fs::read(&key_path).await?)
+    .with_context(|| format!("Failed to parse {}", key_path.display()))?;
 
-  Ok((rustls::Certificate(cert), rustls::PrivateKey(key)))
+  Ok((
+    chain.into_iter().map(rustls::Certificate).collect(),
+    rustls::PrivateKey(key),
+  ))
 }
 
 pub async fn server_thread(app_handle: AppHandle) {
@@ -64,27 +286,49 @@ pub async fn server_thread(app_handle: AppHandle) {
       .unwrap();
   }
 
-  let (cert, key) = get_self_signed_cert(app_handle.clone()).await.unwrap();
+  let config = Arc::new(load_config(&app_handle).await.unwrap());
+  set_log_to_stderr(matches!(config.sink, SinkConfig::Stdout));
 
-  let server_crypto = rustls::ServerConfig::builder()
-    .with_safe_defaults()
-    .with_no_client_auth()
-    .with_single_cert(vec![cert], key)
-    .unwrap();
+  let server_crypto = rustls::ServerConfig::builder().with_safe_defaults();
+  let server_crypto = match &config.client_auth {
+    Some(client_auth) => server_crypto
+      .with_client_cert_verifier(client_verifier(&app_handle, client_auth).await.unwrap()),
+    None => server_crypto.with_no_client_auth(),
+  };
+  let server_crypto = match &config.certificate {
+    Some(files) => {
+      let (cert_chain, key) = get_certificate_chain(app_handle.clone(), files)
+        .await
+        .unwrap();
+      server_crypto.with_single_cert(cert_chain, key).unwrap()
+    }
+    None => {
+      let cert_path = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .unwrap()
+        .join("cert");
+      server_crypto.with_cert_resolver(Arc::new(RotatingCert::load(&cert_path).await.unwrap()))
+    }
+  };
 
-  let server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+  let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+  if let Some(max_connections) = config.limits.max_connections {
+    server_config.concurrent_connections(max_connections);
+  }
 
   let endpoint = quinn::Endpoint::server(server_config, listen_addr).unwrap();
-  println!("Listening on {}", listen_addr);
+  log!("Listening on {}", listen_addr);
 
   while let Some(conn) = endpoint.accept().await {
     let tmp_base_path = tmp_base_path.clone();
+    let config = config.clone();
     let app_handle = app_handle.clone();
     tokio::spawn(async move {
       let remote_addr = conn.remote_address();
-      println!("Connection ({}) open", remote_addr);
-      if let Err(e) = handle_connection(conn, tmp_base_path, app_handle).await {
-        println!("Connection ({}) failed: {}", remote_addr, e.to_string())
+      log!("Connection ({}) open", remote_addr);
+      if let Err(e) = handle_connection(conn, tmp_base_path, config, app_handle).await {
+        log!("Connection ({}) failed: {}", remote_addr, e.to_string())
       }
     });
   }
@@ -95,30 +339,59 @@ pub async fn server_thread(app_handle: AppHandle) {
 async fn handle_connection(
   conn: quinn::Connecting,
   tmp_base_path: String,
+  config: Arc<Config>,
   app_handle: AppHandle,
 ) -> Result<()> {
   let c = conn.await.context("Failed to establish connection")?;
   let remote_addr = c.remote_address();
+  let identity = config
+    .client_auth
+    .as_ref()
+    .and_then(|x| client_identity(&c, x));
+  if let Some(identity) = &identity {
+    log!("Connection ({}) authenticated as {}", remote_addr, identity);
+  }
+  let peer = Arc::new(Peer {
+    id: NEXT_PEER_ID.fetch_add(1, Ordering::Relaxed),
+    remote_addr,
+    identity,
+    rejected_datagrams: AtomicU64::new(0),
+  });
 
   let c_clone = c.clone();
+  let tmp_base_path_clone = tmp_base_path.clone();
+  let peer_clone = peer.clone();
+  let config_clone = config.clone();
   let app_handle_clone = app_handle.clone();
   tokio::spawn(async move {
     loop {
       match c_clone.read_datagram().await {
         Err(e) => {
-          println!(
+          log!(
             "Receive raw datagram ({}) failed: {}",
             remote_addr,
             e.to_string()
           );
+          let rejected = peer_clone.rejected_datagrams.load(Ordering::Relaxed);
+          if rejected > 0 {
+            log!(
+              "Rejected {} datagrams ({}) for unknown or foreign tasks",
+              rejected,
+              peer_clone.remote_addr
+            );
+          }
           return;
         }
         Ok(datagram) => {
-          let tmp_base_path = tmp_base_path.clone();
+          let tmp_base_path = tmp_base_path_clone.clone();
+          let peer = peer_clone.clone();
+          let config = config_clone.clone();
           let app_handle_clone = app_handle_clone.clone();
           tokio::spawn(async move {
-            if let Err(e) = handle_raw_datagram(datagram, tmp_base_path, app_handle_clone).await {
-              println!(
+            if let Err(e) =
+              handle_raw_datagram(datagram, tmp_base_path, peer, config, app_handle_clone).await
+            {
+              log!(
                 "Handle raw datagram ({}) failed: {}",
                 remote_addr,
                 e.to_string()
@@ -134,16 +407,19 @@ async fn handle_connection(
     let stream = c.accept_bi().await;
     let stream = match stream {
       Err(e) => {
+        drop_peer_tasks(peer.id, &tmp_base_path).await;
         return Err(e.into());
       }
       Ok(s) => s,
     };
 
+    let peer = peer.clone();
+    let config = config.clone();
     let app_handle = app_handle.clone();
 
     tokio::spawn(async move {
-      if let Err(e) = handle_stream(stream, app_handle).await {
-        println!("Stream ({}) failed: {}", remote_addr, e.to_string())
+      if let Err(e) = handle_stream(stream, peer, config, app_handle).await {
+        log!("Stream ({}) failed: {}", remote_addr, e.to_string())
       }
     });
   }
@@ -152,6 +428,8 @@ async fn handle_connection(
 async fn handle_raw_datagram(
   datagram: bytes::Bytes,
   tmp_base_path: String,
+  peer: Arc<Peer>,
+  config: Arc<Config>,
   app_handle: AppHandle,
 ) -> Result<()> {
   let datagram_clone = datagram.clone();
@@ -164,56 +442,331 @@ async fn handle_raw_datagram(
 
   let uuid = cur.read_u128().await?;
   let block_id = cur.read_u32().await?;
+  let encoded_len = cur.read_u32().await? as u64;
   let packet = datagram.slice((cur.position() as usize)..datagram.len());
 
-  let mut tasks = TASKS.lock().await;
+  let Some(task) = get_task(uuid, &peer).await else {
+    peer.rejected_datagrams.fetch_add(1, Ordering::Relaxed);
+    return Ok(());
+  };
+
+  let (params, file_size, packets) = {
+    let mut task = task.lock().await;
+    let task = &mut *task;
 
-  if let Some(task) = tasks.get_mut(&uuid) {
-    if task.rebuilt_blocks.contains(&block_id) {
+    if task.closed || task.rebuilt_blocks.contains(&block_id) {
       return Ok(());
     }
 
-    if task.recv_blocks.contains_key(&block_id) {
-      let recv_map = task.recv_blocks.get_mut(&block_id).unwrap();
-      let inserted = recv_map.insert(packet);
-
-      if (recv_map.len() >= DATA_PACKET_COUNT_PER_BLOCK) && inserted {
-        let result = decode_block(
-          uuid,
-          block_id,
-          task.file_size,
-          Vec::from_iter(recv_map.iter().map(|x| x.clone())),
-          &tmp_base_path,
-        )
+    // Sent before a symbol size change
+    if packet.len() != task.params.symbol_size as usize + PAYLOAD_ID_LENGTH {
+      return Ok(());
+    }
+
+    // A stream may not grow past the max file size before it announces its size
+    let max_file_size = config.limits.max_file_size.unwrap_or(u64::MAX);
+    if block_id >= task.params.block_count(task.file_size.min(max_file_size)) {
+      return Err(anyhow!("invalid block ID"));
+    }
+
+    if !task
+      .params
+      .is_valid_encoded_len(task.file_size, block_id, encoded_len)
+    {
+      return Err(anyhow!("invalid encoded length"));
+    }
+
+    // A stream counts against the space and quota with every block it adds
+    let received = (block_id as u64 + 1).saturating_mul(task.params.block_size);
+    if !task.is_size_known() && received > task.reservation.bytes() {
+      let base_path = app_handle.path_resolver().app_data_dir().unwrap();
+      let recv_dir = upload_dir(&app_handle, &config, task.route_dir.as_deref());
+      let result = task
+        .reservation
+        .resize(&config, &base_path, recv_dir, received)
         .await;
-        match result {
-          Ok(()) => {
-            task.rebuilt_blocks.insert(block_id);
-            task.recv_blocks.remove(&block_id);
-            emit_task_event(&app_handle, uuid, task, TaskStatus::Recv)
-          }
-          Err(_) => {
-            println!(
-              "Failed to decode block {}: {}/{}",
-              block_id,
-              recv_map.len(),
-              DATA_PACKET_COUNT_PER_BLOCK
-            )
-          }
-        }
+      if let Err(e) = result {
+        log!("Drop streamed upload of {}: {}", task.filename, e);
+        give_up_task(uuid, task, Path::new(&tmp_base_path)).await;
+        return Ok(());
       }
-    } else {
-      let mut recv = HashSet::new();
-      recv.insert(packet);
-      task.recv_blocks.insert(block_id, recv);
+    }
+
+    // Every packet of a block must encode the same data
+    if task
+      .encoded_lens
+      .get(&block_id)
+      .is_some_and(|x| *x != encoded_len)
+    {
+      return Ok(());
+    }
+
+    if task
+      .recv_blocks
+      .get(&block_id)
+      .is_some_and(|x| x.contains(&packet))
+    {
+      return Ok(());
+    }
+
+    // Over the buffer limits, the packet is lost like any other
+    if !task.reserve(block_id, packet.len() as u64, &config) {
+      return Ok(());
+    }
+
+    task.buffer_packet(block_id, packet);
+    task.encoded_lens.insert(block_id, encoded_len);
+    let recv_map = &task.recv_blocks[&block_id];
+    let source_symbols = task.params.encoded_source_symbols(encoded_len) as usize;
+
+    // Packets arriving while the block decodes are kept for a retry if decoding fails
+    if recv_map.len() < source_symbols || !task.decoding_blocks.insert(block_id) {
+      return Ok(());
+    }
+
+    (
+      task.params,
+      task.file_size,
+      Vec::from_iter(recv_map.iter().cloned()),
+    )
+  };
+
+  let packet_count = packets.len();
+  let result = DECODE_POOL
+    .run(decode_block(
+      uuid,
+      block_id,
+      file_size,
+      encoded_len,
+      &params,
+      packets,
+      &tmp_base_path,
+    ))
+    .await
+    .map_err(|e| e.to_string());
+
+  let mut task = task.lock().await;
+  task.decoding_blocks.remove(&block_id);
+  match result {
+    // Given up while the block decoded, so it's removed again
+    Ok(()) if task.closed => {
+      remove_tmp_dir(&Path::new(&tmp_base_path).join(uuid.to_string())).await
+    }
+    Ok(()) => {
+      task.rebuild_block(block_id);
+      emit_task_event(&app_handle, uuid, &task, TaskStatus::Recv)
+    }
+    Err(_) => {
+      log!(
+        "Failed to decode block {}: {}/{}",
+        block_id,
+        packet_count,
+        params.encoded_source_symbols(encoded_len)
+      )
     }
   }
+  task.decoded.notify_waiters();
 
   Ok(())
 }
 
+/// Check the params of an upload, on request and when they change. The packets of a window of
+/// interleaved blocks must fit in the buffers, or the blocks are never rebuilt.
+fn check_params(params: &TransferParams, config: &Config) -> Result<(), String> {
+  params.validate()?;
+  let buffer_size = params.window_buffer_size();
+  let max_buffer = config.max_task_buffer.min(config.max_total_buffer);
+  if buffer_size > max_buffer {
+    return Err(format!(
+      "{} interleaved blocks need {} bytes of buffer, at most {} allowed",
+      params.interleave_depth, buffer_size, max_buffer
+    ));
+  }
+  Ok(())
+}
+
+/// The header of an encrypted upload must describe the file being uploaded, or the recipient
+/// couldn't decrypt it.
+fn check_encryption_header(
+  params: &TransferParams,
+  file_size: u64,
+  header: &[u8],
+) -> Result<(), String> {
+  if !params.encrypted {
+    if !header.is_empty() {
+      return Err("encryption header without the encrypted flag".to_string());
+    }
+    return Ok(());
+  }
+
+  // The header holds the file size, which a streamed upload doesn't know yet
+  if file_size == UNKNOWN_FILE_SIZE {
+    return Err("streamed uploads can't be encrypted".to_string());
+  }
+
+  let mut reader = header;
+  let parsed = EncryptionHeader::read_from(&mut reader).map_err(|e| e.to_string())?;
+  if !reader.is_empty() {
+    return Err("trailing data after the encryption header".to_string());
+  }
+  if parsed.file_size != file_size || parsed.block_size != params.block_size {
+    return Err("encryption header does not match the upload".to_string());
+  }
+  Ok(())
+}
+
+/// Directory an upload is kept in: its route's, else the sink's if it keeps files in one.
+fn upload_dir(app_handle: &AppHandle, config: &Config, route_dir: Option<&str>) -> Option<PathBuf> {
+  let base_path = app_handle.path_resolver().app_data_dir().unwrap();
+  match route_dir {
+    Some(dir) => Some(base_path.join(dir)),
+    None => sink_dir(&config.sink, &base_path),
+  }
+}
+
+/// Path of the previously received version of a file in `dir`. Only plain file names are
+/// accepted, so a client can't read other files.
+fn previous_version_path(dir: Option<PathBuf>, filename: &str) -> Option<PathBuf> {
+  if !is_plain_filename(filename) {
+    return None;
+  }
+  let path = dir?.join(filename);
+  path.is_file().then_some(path)
+}
+
+/// Longest filename read from a request, whatever the configured limit.
+const MAX_FILENAME_READ: usize = 1024;
+
+/// Read the UTF-8 filename ending a request. A too long filename is reported to the client.
+async fn read_filename(
+  recv: &mut quinn::RecvStream,
+  limits: &Limits,
+) -> Result<Result<String, RequestError>> {
+  let filename = match recv.read_to_end(MAX_FILENAME_READ).await {
+    Ok(filename) => filename,
+    Err(quinn::ReadToEndError::TooLong) => return Ok(Err(RequestError::FilenameTooLong)),
+    Err(e) => return Err(e.into()),
+  };
+  if filename.len() > limits.max_filename_length {
+    return Ok(Err(RequestError::FilenameTooLong));
+  }
+  Ok(Ok(String::from_utf8(filename)?))
+}
+
+async fn read_tag(recv: &mut quinn::RecvStream) -> Result<Option<String>> {
+  let mut tag = vec![0; recv.read_u16().await? as usize];
+  recv.read_exact(&mut tag).await?;
+  let tag = String::from_utf8(tag)?;
+  Ok((!tag.is_empty()).then_some(tag))
+}
+
+/// Merge the rebuilt blocks of a task into `merged_path` and hand the file to the sink.
+async fn store_merged(
+  config: &Config,
+  base_path: &Path,
+  uuid: u128,
+  task: &Task,
+  merged_path: PathBuf,
+) -> Result<ReceivedFile> {
+  let tmp_path = base_path.join("tmp").join(uuid.to_string());
+  fs::create_dir_all(base_path.join("tmp")).await?;
+  let mut output = File::create(&merged_path).await?;
+  let filename = match &task.encryption_header {
+    Some(header) => {
+      output.write_all(header).await?;
+      format!("{}.{}", task.filename, ENCRYPTED_EXTENSION)
+    }
+    None => task.filename.clone(),
+  };
+  for block_id in 0..task.params.block_count(task.file_size) {
+    let mut input = File::open(tmp_path.join(block_id.to_string())).await?;
+    io::copy(&mut input, &mut output).await?;
+  }
+  output.flush().await?;
+  drop(output);
+
+  let received = ReceivedFile {
+    file_size: fs::metadata(&merged_path).await?.len(),
+    sha256: file_hash(&merged_path).await?,
+    path: merged_path,
+    filename,
+    uuid,
+    token_name: task.token_name.clone(),
+    client_identity: task.client_identity.clone(),
+  };
+  let sink = match &task.route_dir {
+    Some(dir) => Arc::new(DirSink {
+      path: base_path.join(dir),
+    }),
+    None => create_sink(&config.sink, base_path),
+  };
+  sink.receive(&received).await?;
+  Ok(received)
+}
+
+/// Merge the rebuilt blocks of a task and hand the file to the configured sink. The blocks and
+/// the merged file are removed whether or not this succeeds. Returns the environment of the
+/// post-receive hooks.
+async fn merge_task(
+  app_handle: &AppHandle,
+  config: &Config,
+  uuid: u128,
+  task: &Task,
+) -> Result<Vec<(&'static str, String)>> {
+  emit_task_event(app_handle, uuid, task, TaskStatus::Merge);
+
+  let base_path = app_handle.path_resolver().app_data_dir().unwrap();
+  let tmp_path = base_path.join("tmp").join(uuid.to_string());
+  let merged_path = base_path.join("tmp").join(format!("{}.merged", uuid));
+  let result = store_merged(config, &base_path, uuid, task, merged_path.clone()).await;
+  if fs::try_exists(&merged_path).await? {
+    fs::remove_file(&merged_path).await?;
+  }
+  if fs::try_exists(&tmp_path).await? {
+    fs::remove_dir_all(tmp_path).await?;
+  }
+  let received = match result {
+    Ok(received) => received,
+    Err(e) => {
+      task.reservation.release();
+      emit_task_event(app_handle, uuid, task, TaskStatus::Failed);
+      return Err(e);
+    }
+  };
+  log!("Merged successfully");
+  if let Err(e) = record_usage(&base_path, task.reservation.account(), received.file_size).await {
+    log!("Failed to record usage: {}", e);
+  }
+  // Counted in the usage now, and the hooks may run a while
+  task.reservation.release();
+
+  let mut env = received.env();
+  if let Some(dir) = upload_dir(app_handle, config, task.route_dir.as_deref()) {
+    let path = dir.join(&received.filename);
+    env.push(("QFT_PATH", path.to_string_lossy().to_string()));
+  }
+  Ok(env)
+}
+
+/// Run the post-receive hooks of a merged task, which is no longer in `TASKS`. The task is only
+/// locked to record the results, so slow hooks hold up nothing else.
+async fn run_task_hooks(
+  app_handle: &AppHandle,
+  config: &Config,
+  uuid: u128,
+  task: &Mutex<Task>,
+  env: Vec<(&'static str, String)>,
+) {
+  let hook_results = run_hooks(&config.hooks, &env).await;
+  let mut task = task.lock().await;
+  task.hook_results = hook_results;
+  emit_task_event(app_handle, uuid, &task, TaskStatus::Done);
+}
+
 async fn handle_stream(
   (mut send, mut recv): (quinn::SendStream, quinn::RecvStream),
+  peer: Arc<Peer>,
+  config: Arc<Config>,
   app_handle: AppHandle,
 ) -> Result<()> {
   let flag = recv.read_u8().await?;
@@ -221,63 +774,421 @@ async fn handle_stream(
   match flag {
     FLAG_REQUEST_ID => {
       let file_size = recv.read_u64().await?;
-      let filename = String::from_utf8(recv.read_to_end(1024).await?)?;
+      let params = match TransferParams::read_from(&mut recv).await {
+        Ok(params) => check_params(&params, &config).map(|_| params),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(e.to_string()),
+        Err(e) => return Err(e.into()),
+      };
+      let mut token = vec![0; recv.read_u16().await? as usize];
+      recv.read_exact(&mut token).await?;
+      let mut encryption_header = vec![0; recv.read_u16().await? as usize];
+      recv.read_exact(&mut encryption_header).await?;
+      let params = params.and_then(|params| {
+        check_encryption_header(&params, file_size, &encryption_header).map(|_| params)
+      });
+      let tag = read_tag(&mut recv).await?;
+      let filename = match read_filename(&mut recv, &config.limits).await? {
+        Ok(filename) => filename,
+        Err(e) => {
+          log!("Reject upload request ({}): {}", peer.remote_addr, e);
+          send.write_u8(FLAG_ERROR).await?;
+          send.write_u8(e.code()).await?;
+          return Ok(());
+        }
+      };
+      let params = params.and_then(|params| {
+        is_plain_filename(&filename)
+          .then_some(params)
+          .ok_or_else(|| "filename must be a plain file name".to_string())
+      });
+
+      let token_name = match config.authorize(token) {
+        Ok(token_name) => token_name,
+        Err(e) => {
+          log!(
+            "Reject upload request of {} ({}): {}",
+            filename,
+            peer.remote_addr,
+            e
+          );
+          send.write_u8(FLAG_ERROR).await?;
+          send.write_u8(e.code()).await?;
+          return Ok(());
+        }
+      };
+
+      let params = match params {
+        Ok(params) => params,
+        Err(e) => {
+          log!("Reject upload request of {}: {}", filename, e);
+          send.write_u8(FLAG_ERROR).await?;
+          send.write_u8(RequestError::InvalidParams.code()).await?;
+          return Ok(());
+        }
+      };
+
+      let route_dir =
+        match route_upload(&config, peer.identity.as_deref(), tag.as_deref(), &filename) {
+          Ok(route_dir) => route_dir,
+          Err(e) => {
+            log!(
+              "Reject upload request of {} ({}): {}",
+              filename,
+              peer.remote_addr,
+              e
+            );
+            send.write_u8(FLAG_ERROR).await?;
+            send.write_u8(e.code()).await?;
+            return Ok(());
+          }
+        };
 
       let uuid = Uuid::new_v4();
 
+      let base_path = app_handle.path_resolver().app_data_dir().unwrap();
+      let recv_dir = upload_dir(&app_handle, &config, route_dir.as_deref());
+      let result = reserve(
+        &config,
+        &base_path,
+        recv_dir,
+        uuid.as_u128(),
+        peer.id,
+        Account::new(peer.identity.as_deref(), token_name.as_deref()),
+        file_size,
+      )
+      .await;
+      let reservation = match result {
+        Ok(reservation) => reservation,
+        Err(e) => {
+          log!(
+            "Reject upload request of {} ({}): {}",
+            filename,
+            peer.remote_addr,
+            e
+          );
+          send.write_u8(FLAG_ERROR).await?;
+          send.write_u8(e.code()).await?;
+          return Ok(());
+        }
+      };
+
       let task = Task {
+        token_name,
+        client_identity: peer.identity.clone(),
         filename,
         file_size,
+        params,
+        encryption_header: params.encrypted.then_some(encryption_header),
+        route_dir,
         rebuilt_blocks: HashSet::new(),
         recv_blocks: HashMap::new(),
+        encoded_lens: HashMap::new(),
+        recv_order: VecDeque::new(),
+        buffered_bytes: 0,
+        decoding_blocks: HashSet::new(),
+        decoded: Arc::new(Notify::new()),
+        hook_results: vec![],
+        reservation,
+        closed: false,
       };
 
       emit_task_event(&app_handle, uuid.as_u128(), &task, TaskStatus::Recv);
 
-      let mut tasks = TASKS.lock().await;
-      tasks.insert(uuid.as_u128(), task);
+      // Nothing to upload for an empty file, so it is done right away
+      if task.file_size == 0 {
+        let env = match merge_task(&app_handle, &config, uuid.as_u128(), &task).await {
+          Ok(env) => env,
+          Err(e) => {
+            log!("Failed to store {}: {:#}", task.filename, e);
+            send.write_u8(FLAG_ERROR).await?;
+            send.write_u8(RequestError::StoreFailed.code()).await?;
+            return Ok(());
+          }
+        };
+        send.write_u8(FLAG_OK).await?;
+        send.write_all(&uuid.into_bytes()).await?;
+        let task = Mutex::new(task);
+        run_task_hooks(&app_handle, &config, uuid.as_u128(), &task, env).await;
+        return Ok(());
+      }
+
+      let mut tasks = TASKS.write().await;
+      tasks.insert(uuid.as_u128(), (peer.id, Arc::new(Mutex::new(task))));
+      drop(tasks);
 
+      send.write_u8(FLAG_OK).await?;
       send.write_all(&uuid.into_bytes()).await?;
       Ok(())
     }
 
+    FLAG_UPDATE_PARAMS => {
+      let uuid = recv.read_u128().await?;
+      let symbol_size = recv.read_u16().await?;
+
+      let mut task = lock_task(uuid, &peer).await.context("Invalid ID")?;
+
+      let params = TransferParams {
+        symbol_size,
+        ..task.params
+      };
+      if let Err(e) = check_params(&params, &config) {
+        log!("Reject params update of {}: {}", task.filename, e);
+        send.write_u8(FLAG_ERROR).await?;
+        send.write_u8(RequestError::InvalidParams.code()).await?;
+        return Ok(());
+      }
+
+      log!(
+        "Symbol size of {} changed: {} -> {}",
+        task.filename,
+        task.params.symbol_size,
+        symbol_size
+      );
+      task.params = params;
+      task.drop_all_blocks();
+
+      send.write_u8(FLAG_OK).await?;
+      Ok(())
+    }
+
+    FLAG_BLOCK_HASHES => {
+      let block_size = recv.read_u64().await?;
+      let mut token = vec![0; recv.read_u16().await? as usize];
+      recv.read_exact(&mut token).await?;
+      let tag = read_tag(&mut recv).await?;
+      let filename = match read_filename(&mut recv, &config.limits).await? {
+        Ok(filename) => filename,
+        Err(e) => {
+          log!("Reject block hashes ({}): {}", peer.remote_addr, e);
+          send.write_u8(FLAG_ERROR).await?;
+          send.write_u8(e.code()).await?;
+          return Ok(());
+        }
+      };
+
+      let result = config.authorize(token).and_then(|_| {
+        if !(MIN_BLOCK_SIZE..=MAX_PARTITIONED_BLOCK_SIZE).contains(&block_size) {
+          return Err(RequestError::InvalidParams);
+        }
+        let route_dir = route_upload(&config, peer.identity.as_deref(), tag.as_deref(), &filename)?;
+        let dir = upload_dir(&app_handle, &config, route_dir.as_deref());
+        previous_version_path(dir, &filename).ok_or(RequestError::NotFound)
+      });
+      let path = match result {
+        Ok(path) => path,
+        Err(e) => {
+          log!("Reject block hashes of {}: {}", filename, e);
+          send.write_u8(FLAG_ERROR).await?;
+          send.write_u8(e.code()).await?;
+          return Ok(());
+        }
+      };
+
+      let file_size = fs::metadata(&path).await?.len();
+      let hashes = block_hashes(&path, block_size).await?;
+      log!("Send {} block hashes of {}", hashes.len(), filename);
+
+      send.write_u8(FLAG_OK).await?;
+      send.write_u64(file_size).await?;
+      send.write_u32(hashes.len() as u32).await?;
+      for hash in hashes {
+        send.write_all(&hash).await?;
+      }
+      Ok(())
+    }
+
+    FLAG_REUSE_BLOCKS => {
+      let uuid = recv.read_u128().await?;
+      let mut base_filename = vec![0; recv.read_u16().await? as usize];
+      recv.read_exact(&mut base_filename).await?;
+      let base_filename = String::from_utf8(base_filename)?;
+
+      let task = lock_task(uuid, &peer).await.context("Invalid ID")?;
+
+      let block_count = task.params.block_count(task.file_size);
+      let count = recv.read_u32().await?;
+      if count > block_count {
+        return Err(anyhow!("too many reused blocks"));
+      }
+      let mut blocks = vec![];
+      for _ in 0..count {
+        blocks.push(ReusedBlock::read_from(&mut recv).await?);
+      }
+
+      // Blocks of an encrypted upload are ciphertext, which the previous version can't provide.
+      // The blocks of a streamed upload aren't known in advance.
+      if task.params.encrypted || !task.is_size_known() {
+        log!("Reject reusing blocks for {}", task.filename);
+        send.write_u8(FLAG_ERROR).await?;
+        send.write_u8(RequestError::InvalidParams.code()).await?;
+        return Ok(());
+      }
+      let dir = upload_dir(&app_handle, &config, task.route_dir.as_deref());
+      let Some(base_path) = previous_version_path(dir, &base_filename) else {
+        log!("Reject reusing blocks of {}: not found", base_filename);
+        send.write_u8(FLAG_ERROR).await?;
+        send.write_u8(RequestError::NotFound.code()).await?;
+        return Ok(());
+      };
+
+      let tmp_path = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .unwrap()
+        .join("tmp")
+        .join(uuid.to_string());
+      let blocks: Vec<(ReusedBlock, u64)> = blocks
+        .into_iter()
+        .filter(|x| {
+          x.block_id < block_count
+            && !task.rebuilt_blocks.contains(&x.block_id)
+            && !task.decoding_blocks.contains(&x.block_id)
+        })
+        .map(|x| (x, task.params.block_len(task.file_size, x.block_id)))
+        .collect();
+      let block_size = task.params.block_size;
+      // Copying blocks takes a while, and the datagrams of the task must not wait for it
+      drop(task);
+
+      let base = open_base(&base_path).await?;
+      let mut copied = vec![];
+      for (block, block_len) in blocks {
+        if reuse_block(&base, block_size, &block, block_len, &tmp_path).await? {
+          copied.push(block.block_id);
+        }
+      }
+
+      let Some(mut task) = lock_task(uuid, &peer).await else {
+        // Given up meanwhile, so the copied blocks are removed again
+        remove_tmp_dir(&tmp_path).await;
+        return Err(anyhow!("Invalid ID"));
+      };
+      let mut reused = 0;
+      for block_id in copied {
+        // Rebuilt from datagrams meanwhile
+        if task.rebuilt_blocks.contains(&block_id) || task.decoding_blocks.contains(&block_id) {
+          continue;
+        }
+        task.rebuild_block(block_id);
+        reused += 1;
+      }
+
+      log!(
+        "Reused {}/{} blocks of {} for {}",
+        reused,
+        count,
+        base_filename,
+        task.filename
+      );
+      emit_task_event(&app_handle, uuid, &task, TaskStatus::Recv);
+
+      send.write_u8(FLAG_OK).await?;
+      send.write_u32(reused).await?;
+      Ok(())
+    }
+
+    FLAG_FILE_SIZE => {
+      let uuid = recv.read_u128().await?;
+      let file_size = recv.read_u64().await?;
+
+      let mut task = lock_task(uuid, &peer).await.context("Invalid ID")?;
+
+      // Blocks past the end and a short last block were received as full blocks
+      let block_count = task.params.block_count(file_size);
+      let last_block_short = file_size % task.params.block_size != 0;
+      let was_received_full = |block_id: u32| {
+        block_id >= block_count || (last_block_short && block_id == block_count - 1)
+      };
+      if task.is_size_known()
+        || file_size == UNKNOWN_FILE_SIZE
+        || task
+          .rebuilt_blocks
+          .iter()
+          .chain(task.decoding_blocks.iter())
+          .any(|x| was_received_full(*x))
+      {
+        log!("Reject file size {} of {}", file_size, task.filename);
+        send.write_u8(FLAG_ERROR).await?;
+        send.write_u8(RequestError::InvalidParams.code()).await?;
+        return Ok(());
+      }
+
+      let base_path = app_handle.path_resolver().app_data_dir().unwrap();
+      let recv_dir = upload_dir(&app_handle, &config, task.route_dir.as_deref());
+      let result = task
+        .reservation
+        .resize(&config, &base_path, recv_dir, file_size)
+        .await;
+      if let Err(e) = result {
+        log!("Reject file size {} of {}: {}", file_size, task.filename, e);
+        send.write_u8(FLAG_ERROR).await?;
+        send.write_u8(e.code()).await?;
+
+        // The stream is too large, so the task is given up
+        give_up_task(uuid, &mut task, &base_path.join("tmp")).await;
+        return Ok(());
+      }
+
+      let stale: Vec<u32> = task
+        .recv_order
+        .iter()
+        .copied()
+        .filter(|x| was_received_full(*x))
+        .collect();
+      for block_id in stale {
+        task.drop_block(block_id);
+      }
+      task.file_size = file_size;
+      log!("File size of {}: {}", task.filename, file_size);
+      emit_task_event(&app_handle, uuid, &task, TaskStatus::Recv);
+
+      send.write_u8(FLAG_OK).await?;
+      Ok(())
+    }
+
     FLAG_UPLOAD_COMPLETE => {
       let uuid = recv.read_u128().await?;
 
-      let mut tasks = TASKS.lock().await;
-      let task = tasks.get(&uuid).context("Invalid ID")?;
+      let shared = get_task(uuid, &peer).await.context("Invalid ID")?;
 
-      let total_blocks = (task.file_size as f32 / BLOCK_SIZE as f32).ceil() as usize;
+      // Wait for blocks still in the decode pool, so they aren't reported missing
+      let mut task = lock_idle(&shared).await;
+      if task.closed {
+        return Err(anyhow!("Invalid ID"));
+      }
 
-      println!(
+      if !task.is_size_known() {
+        return Err(anyhow!("size of streamed upload not announced"));
+      }
+      let total_blocks = task.params.block_count(task.file_size) as usize;
+
+      log!(
         "Client upload completed. Rebuilt blocks: {}/{}",
         task.rebuilt_blocks.len(),
         total_blocks
       );
 
       if task.rebuilt_blocks.len() == total_blocks {
-        send.write_u8(FLAG_FILE_DECODE_OK).await?;
-        println!("Received successfully");
-
-        emit_task_event(&app_handle, uuid, task, TaskStatus::Merge);
-
-        let base_path =
-          PathBuf::from(app_handle.path_resolver().app_data_dir().unwrap()).join("recv");
-        let tmp_path = PathBuf::from(app_handle.path_resolver().app_data_dir().unwrap())
-          .join("tmp")
-          .join(uuid.to_string());
-        fs::create_dir_all(&base_path).await?;
-        let mut output = File::create(base_path.join(&task.filename)).await?;
-        for block_id in 0..total_blocks {
-          let mut input = File::open(tmp_path.join(block_id.to_string())).await?;
-          io::copy(&mut input, &mut output).await?;
-        }
+        log!("Received successfully");
 
-        emit_task_event(&app_handle, uuid, task, TaskStatus::Done);
-        println!("Merged successfully");
+        // Given up before merging, so a failed merge doesn't keep counting against the limits
+        task.closed = true;
+        task.drop_all_blocks();
+        TASKS.write().await.remove(&uuid);
 
-        fs::remove_dir_all(tmp_path).await?;
-        tasks.remove(&uuid).unwrap();
+        // Only confirmed once the sink has the file
+        let env = match merge_task(&app_handle, &config, uuid, &task).await {
+          Ok(env) => env,
+          Err(e) => {
+            log!("Failed to store {}: {:#}", task.filename, e);
+            send.write_u8(FLAG_FILE_DECODE_ERROR).await?;
+            send.write_u32(0).await?;
+            return Ok(());
+          }
+        };
+        send.write_u8(FLAG_FILE_DECODE_OK).await?;
+        drop(task);
+        run_task_hooks(&app_handle, &config, uuid, &shared, env).await;
         return Ok(());
       }
 
@@ -309,3 +1220,97 @@ async fn handle_stream(
     _ => Err(anyhow!("invalid flag")),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::space::{is_reserved, reserve};
+
+  const FILE_SIZE: u64 = 4 * 1024 * 1024;
+  const PACKET_LEN: u64 = 1024;
+
+  /// Add an upload of `peer_id` with a rebuilt block on disk and a partial block buffered.
+  async fn start_upload(tmp_base_path: &Path, peer_id: u64) -> u128 {
+    let uuid = Uuid::new_v4().as_u128();
+    let reservation = reserve(
+      &Config::default(),
+      tmp_base_path,
+      None,
+      uuid,
+      peer_id,
+      Account::new(None, None),
+      FILE_SIZE,
+    )
+    .await
+    .unwrap();
+    let mut task = Task {
+      token_name: None,
+      client_identity: None,
+      filename: "test".into(),
+      file_size: FILE_SIZE,
+      params: TransferParams::default(),
+      encryption_header: None,
+      route_dir: None,
+      recv_blocks: HashMap::new(),
+      encoded_lens: HashMap::new(),
+      recv_order: VecDeque::new(),
+      buffered_bytes: 0,
+      rebuilt_blocks: HashSet::new(),
+      decoding_blocks: HashSet::new(),
+      decoded: Arc::new(Notify::new()),
+      hook_results: vec![],
+      reservation,
+      closed: false,
+    };
+
+    let tmp_path = tmp_base_path.join(uuid.to_string());
+    fs::create_dir_all(&tmp_path).await.unwrap();
+    fs::write(tmp_path.join("0"), vec![0; task.params.block_size as usize])
+      .await
+      .unwrap();
+    task.rebuild_block(0);
+    task.buffer_packet(1, vec![0; PACKET_LEN as usize].into());
+
+    TASKS
+      .write()
+      .await
+      .insert(uuid, (peer_id, Arc::new(Mutex::new(task))));
+    uuid
+  }
+
+  #[tokio::test]
+  async fn tasks_of_closed_connections_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let tmp_base_path = dir.path().to_str().unwrap();
+    let (closed_peer, open_peer) = (u64::MAX, u64::MAX - 1);
+    let buffered = BUFFERED_BYTES.load(Ordering::Relaxed);
+
+    let closed_upload = start_upload(dir.path(), closed_peer).await;
+    let open_upload = start_upload(dir.path(), open_peer).await;
+    assert_eq!(
+      BUFFERED_BYTES.load(Ordering::Relaxed),
+      buffered + 2 * PACKET_LEN
+    );
+
+    // The connection closes mid-upload, while a request still holds the task
+    let pending_request = TASKS.read().await[&closed_upload].1.clone();
+    drop_peer_tasks(closed_peer, tmp_base_path).await;
+
+    assert!(pending_request.lock().await.closed);
+    assert!(!is_reserved(closed_upload));
+    assert!(!TASKS.read().await.contains_key(&closed_upload));
+    assert!(!dir.path().join(closed_upload.to_string()).exists());
+    assert_eq!(
+      BUFFERED_BYTES.load(Ordering::Relaxed),
+      buffered + PACKET_LEN
+    );
+
+    // Uploads of other connections carry on
+    assert!(is_reserved(open_upload));
+    assert!(TASKS.read().await.contains_key(&open_upload));
+    assert!(dir.path().join(open_upload.to_string()).exists());
+
+    drop_peer_tasks(open_peer, tmp_base_path).await;
+    assert_eq!(BUFFERED_BYTES.load(Ordering::Relaxed), buffered);
+  }
+}
@@ -0,0 +1,143 @@
+use std::{
+  future::Future,
+  io::Write,
+  path::{Path, PathBuf},
+  pin::Pin,
+  process::Stdio,
+  sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{fs, process::Command, task};
+
+use crate::config::SinkConfig;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A completed transfer, merged into a temporary file.
+pub struct ReceivedFile {
+  pub path: PathBuf,
+  /// Name the client uploaded the file as, with `.qftenc` appended if it's encrypted.
+  pub filename: String,
+  pub file_size: u64,
+  /// SHA-256 of the file as lowercase hex.
+  pub sha256: String,
+  pub uuid: u128,
+  pub token_name: Option<String>,
+  pub client_identity: Option<String>,
+}
+
+impl ReceivedFile {
+  /// Metadata of the file in environment variables, for commands run on it.
+  pub fn env(&self) -> Vec<(&'static str, String)> {
+    vec![
+      ("QFT_FILENAME", self.filename.clone()),
+      ("QFT_FILE_SIZE", self.file_size.to_string()),
+      ("QFT_SHA256", self.sha256.clone()),
+      ("QFT_UUID", self.uuid.to_string()),
+      (
+        "QFT_TOKEN_NAME",
+        self.token_name.clone().unwrap_or_default(),
+      ),
+      (
+        "QFT_CLIENT_IDENTITY",
+        self.client_identity.clone().unwrap_or_default(),
+      ),
+    ]
+  }
+}
+
+/// Where completed transfers go. The temporary file is removed once the sink returns, unless
+/// the sink moved it.
+pub trait Sink: Send + Sync {
+  fn receive<'a>(&'a self, file: &'a ReceivedFile) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Keeps received files in a directory, replacing earlier versions.
+pub struct DirSink {
+  pub path: PathBuf,
+}
+
+impl Sink for DirSink {
+  fn receive<'a>(&'a self, file: &'a ReceivedFile) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async move {
+      fs::create_dir_all(&self.path).await?;
+      let target = self.path.join(&file.filename);
+      // Renaming fails across file systems
+      if fs::rename(&file.path, &target).await.is_err() {
+        fs::copy(&file.path, &target).await?;
+      }
+      Ok(())
+    })
+  }
+}
+
+/// Writes received files to stdout, each after a `QFT <size> <filename>` header line. Log lines
+/// go to stderr while this sink is configured.
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+  fn receive<'a>(&'a self, file: &'a ReceivedFile) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async move {
+      let mut input = std::fs::File::open(&file.path)?;
+      let header = format!("QFT {} {}\n", file.file_size, file.filename);
+      task::spawn_blocking(move || {
+        // Held for the whole file, so files of other tasks don't interleave with it
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(header.as_bytes())?;
+        std::io::copy(&mut input, &mut stdout)?;
+        stdout.flush()
+      })
+      .await??;
+      Ok(())
+    })
+  }
+}
+
+/// Runs a command with the path of the received file as last argument and its metadata in
+/// environment variables. The file is removed once the command exits, so it must move or copy
+/// the file to keep it.
+pub struct CommandSink {
+  pub program: String,
+  pub args: Vec<String>,
+}
+
+impl Sink for CommandSink {
+  fn receive<'a>(&'a self, file: &'a ReceivedFile) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async move {
+      let status = Command::new(&self.program)
+        .args(&self.args)
+        .arg(&file.path)
+        .envs(file.env())
+        .stdin(Stdio::null())
+        .status()
+        .await?;
+      if !status.success() {
+        return Err(anyhow!("{} exited with {}", self.program, status));
+      }
+      Ok(())
+    })
+  }
+}
+
+/// Directory the configured sink keeps files in, which holds the previous versions for delta
+/// uploads. Relative paths are relative to the app data directory.
+pub fn sink_dir(config: &SinkConfig, base_path: &Path) -> Option<PathBuf> {
+  match config {
+    SinkConfig::Dir { path } => Some(base_path.join(path)),
+    _ => None,
+  }
+}
+
+pub fn create_sink(config: &SinkConfig, base_path: &Path) -> Arc<dyn Sink> {
+  match config {
+    SinkConfig::Dir { path } => Arc::new(DirSink {
+      path: base_path.join(path),
+    }),
+    SinkConfig::Stdout => Arc::new(StdoutSink),
+    SinkConfig::Command { program, args } => Arc::new(CommandSink {
+      program: program.clone(),
+      args: args.clone(),
+    }),
+  }
+}
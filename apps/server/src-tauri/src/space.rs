@@ -0,0 +1,547 @@
+use std::{
+  collections::HashMap,
+  io,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use rust_common::{error::RequestError, flags::UNKNOWN_FILE_SIZE};
+use tokio::{fs, sync::Mutex};
+
+use crate::{config::Config, log::log};
+
+/// Serializes updates of `usage.json`, and the checks of new uploads against it.
+static USAGE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Free bytes on the file system of `path`, or of its closest existing ancestor.
+fn available_space(path: &Path) -> io::Result<u64> {
+  let mut path = path;
+  while !path.exists() {
+    path = match path.parent() {
+      Some(parent) => parent,
+      None => break,
+    };
+  }
+  fs2::available_space(path)
+}
+
+/// Temporary bytes a task needs: its blocks, and the merged file next to them.
+fn tmp_bytes(file_size: u64) -> u64 {
+  file_size.saturating_mul(2)
+}
+
+/// Who an upload counts against in `usage.json` and the quotas: its client identity, or else its
+/// token. Anonymous uploads without a token share one account.
+#[derive(Debug, Clone)]
+pub struct Account {
+  client_identity: Option<String>,
+  token_name: Option<String>,
+}
+
+impl Account {
+  pub fn new(client_identity: Option<&str>, token_name: Option<&str>) -> Self {
+    Self {
+      client_identity: client_identity.map(str::to_string),
+      token_name: token_name.map(str::to_string),
+    }
+  }
+
+  /// Key of the account in `usage.json`.
+  fn key(&self) -> String {
+    match (&self.client_identity, &self.token_name) {
+      (Some(identity), _) => format!("client:{}", identity),
+      (None, Some(name)) => format!("token:{}", name),
+      (None, None) => "anonymous".to_string(),
+    }
+  }
+
+  /// Quota of the account by the first matching rule.
+  fn quota(&self, config: &Config) -> Option<u64> {
+    config
+      .quotas
+      .iter()
+      .find(|x| x.matches(self.client_identity.as_deref(), self.token_name.as_deref()))
+      .map(|x| x.bytes)
+  }
+}
+
+/// What a task in flight counts against the limits.
+struct Entry {
+  peer_id: u64,
+  /// Key of the account.
+  account: String,
+  /// The file size, or the bytes a stream has sent so far until it announces its size.
+  bytes: u64,
+  /// Bytes of rebuilt blocks, which are on disk already.
+  rebuilt_bytes: u64,
+}
+
+type Entries = HashMap<u128, Entry>;
+
+/// Tasks in flight, kept apart from `TASKS` so a new upload is checked without locking every
+/// task. The lock is never held across an await.
+#[derive(Default)]
+struct Ledger {
+  entries: std::sync::Mutex<Entries>,
+}
+
+static LEDGER: Lazy<Ledger> = Lazy::new(Ledger::default);
+
+/// The entry of a task in the ledger, removed once dropped.
+pub struct Reservation {
+  ledger: &'static Ledger,
+  uuid: u128,
+  account: Account,
+  bytes: u64,
+}
+
+impl Reservation {
+  pub fn account(&self) -> &Account {
+    &self.account
+  }
+
+  /// Bytes the task is counted with.
+  pub fn bytes(&self) -> u64 {
+    self.bytes
+  }
+
+  /// Check the task with its new size like a new upload of that size, and count it if it fits.
+  /// Used as a stream grows, and once it announces its size.
+  pub async fn resize(
+    &mut self,
+    config: &Config,
+    base_path: &Path,
+    recv_dir: Option<PathBuf>,
+    bytes: u64,
+  ) -> Result<(), RequestError> {
+    let request = Request {
+      config,
+      base_path,
+      recv_dir,
+      uuid: self.uuid,
+      account: &self.account,
+      bytes,
+    };
+    let uuid = self.uuid;
+    self
+      .ledger
+      .admit(request, |entries| {
+        if let Some(entry) = entries.get_mut(&uuid) {
+          entry.bytes = bytes;
+        }
+        Ok(())
+      })
+      .await?;
+    self.bytes = bytes;
+    Ok(())
+  }
+
+  pub fn add_rebuilt(&self, bytes: u64) {
+    if let Some(entry) = self.ledger.entries.lock().unwrap().get_mut(&self.uuid) {
+      entry.rebuilt_bytes += bytes;
+    }
+  }
+
+  /// Stop counting the task, e.g. once its file is stored.
+  pub fn release(&self) {
+    self.ledger.entries.lock().unwrap().remove(&self.uuid);
+  }
+}
+
+impl Drop for Reservation {
+  fn drop(&mut self) {
+    self.release();
+  }
+}
+
+/// Whether a task is counted in the ledger.
+#[cfg(test)]
+pub fn is_reserved(uuid: u128) -> bool {
+  LEDGER.entries.lock().unwrap().contains_key(&uuid)
+}
+
+/// Space still needed by the tasks in flight, other than `except`. Rebuilt blocks are already
+/// on disk, so they only count once merged.
+struct InFlight {
+  tmp_bytes: u64,
+  recv_bytes: u64,
+  /// Bytes of the in-flight uploads of the account.
+  account_bytes: u64,
+}
+
+fn in_flight(entries: &Entries, except: u128, account: &str) -> InFlight {
+  let mut in_flight = InFlight {
+    tmp_bytes: 0,
+    recv_bytes: 0,
+    account_bytes: 0,
+  };
+  for (_, entry) in entries.iter().filter(|(uuid, _)| **uuid != except) {
+    in_flight.tmp_bytes += tmp_bytes(entry.bytes).saturating_sub(entry.rebuilt_bytes);
+    in_flight.recv_bytes += entry.bytes;
+    if entry.account == account {
+      in_flight.account_bytes += entry.bytes;
+    }
+  }
+  in_flight
+}
+
+/// Bytes received by each account, stored in `usage.json`.
+async fn load_usage(base_path: &Path) -> Result<HashMap<String, u64>> {
+  let path = base_path.join("usage.json");
+  if !fs::try_exists(&path).await? {
+    return Ok(HashMap::new());
+  }
+  let usage = serde_json::from_slice(&fs::read(&path).await?)
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+  Ok(usage)
+}
+
+/// Count a received file against its account. Called before its reservation is released, so the
+/// file always counts in one of them.
+pub async fn record_usage(base_path: &Path, account: &Account, bytes: u64) -> Result<()> {
+  let _guard = USAGE_LOCK.lock().await;
+  let mut usage = load_usage(base_path).await?;
+  *usage.entry(account.key()).or_default() += bytes;
+  fs::write(
+    base_path.join("usage.json"),
+    serde_json::to_vec_pretty(&usage)?,
+  )
+  .await?;
+  Ok(())
+}
+
+/// An upload of `bytes` bytes to check. `recv_dir` is where the upload is kept, if the
+/// sink keeps files.
+struct Request<'a> {
+  config: &'a Config,
+  base_path: &'a Path,
+  recv_dir: Option<PathBuf>,
+  uuid: u128,
+  account: &'a Account,
+  bytes: u64,
+}
+
+impl Ledger {
+  /// Check an upload against the max file size, and its fit on disk next to the tasks in flight
+  /// and in the quota of the account. Then `update` the ledger under the same lock, so concurrent
+  /// requests can't both take the last free bytes. A stream is checked with the bytes it sent so
+  /// far, as it grows.
+  async fn admit(
+    &self,
+    request: Request<'_>,
+    update: impl FnOnce(&mut Entries) -> Result<(), RequestError>,
+  ) -> Result<(), RequestError> {
+    let Request {
+      config,
+      base_path,
+      recv_dir,
+      uuid,
+      account,
+      bytes,
+    } = request;
+    if let Some(max_file_size) = config.limits.max_file_size {
+      if bytes > max_file_size {
+        return Err(RequestError::FileTooLarge);
+      }
+    }
+
+    // Held until the ledger is updated, so a file being stored counts in the usage or the ledger
+    let _guard = USAGE_LOCK.lock().await;
+    let key = account.key();
+    let quota = match account.quota(config) {
+      Some(quota) => match load_usage(base_path).await {
+        Ok(usage) => Some((quota, usage.get(&key).copied())),
+        Err(e) => {
+          log!("Failed to load usage: {}", e);
+          return Err(RequestError::QuotaExceeded);
+        }
+      },
+      None => None,
+    };
+
+    let mut entries = self.entries.lock().unwrap();
+    let in_flight = in_flight(&entries, uuid, &key);
+
+    if let Some((quota, used)) = quota {
+      if used
+        .unwrap_or(0)
+        .saturating_add(in_flight.account_bytes)
+        .saturating_add(bytes)
+        > quota
+      {
+        return Err(RequestError::QuotaExceeded);
+      }
+    }
+
+    let mut needed = vec![(
+      base_path.join("tmp"),
+      tmp_bytes(bytes).saturating_add(in_flight.tmp_bytes),
+    )];
+    if let Some(recv_dir) = recv_dir {
+      needed.push((recv_dir, bytes.saturating_add(in_flight.recv_bytes)));
+    }
+    for (path, needed_bytes) in needed {
+      let available = available_space(&path).map_err(|e| {
+        log!("Failed to get free space of {}: {}", path.display(), e);
+        RequestError::InsufficientSpace
+      })?;
+      let required = needed_bytes.saturating_add(config.min_free_space);
+      if available < required {
+        log!(
+          "Not enough space in {}: {} available, {} required",
+          path.display(),
+          available,
+          required
+        );
+        return Err(RequestError::InsufficientSpace);
+      }
+    }
+
+    update(&mut entries)
+  }
+
+  /// Check a new upload against the limits of the server, and count it in the ledger if it fits.
+  /// The reservation is released once dropped, so every path giving up the task releases it.
+  async fn reserve(
+    &'static self,
+    request: Request<'_>,
+    peer_id: u64,
+  ) -> Result<Reservation, RequestError> {
+    let config = request.config;
+    let uuid = request.uuid;
+    let account = request.account.clone();
+    let bytes = request.bytes;
+    self
+      .admit(request, |entries| {
+        let limits = &config.limits;
+        let peer_tasks = entries.values().filter(|x| x.peer_id == peer_id).count();
+        if limits.max_tasks.is_some_and(|x| entries.len() >= x)
+          || limits
+            .max_tasks_per_connection
+            .is_some_and(|x| peer_tasks >= x)
+        {
+          return Err(RequestError::TooManyTasks);
+        }
+        entries.insert(
+          uuid,
+          Entry {
+            peer_id,
+            account: account.key(),
+            bytes,
+            rebuilt_bytes: 0,
+          },
+        );
+        Ok(())
+      })
+      .await?;
+    Ok(Reservation {
+      ledger: self,
+      uuid,
+      account,
+      bytes,
+    })
+  }
+}
+
+/// Check a new upload against the limits of the server, and count it in the ledger if it fits.
+pub async fn reserve(
+  config: &Config,
+  base_path: &Path,
+  recv_dir: Option<PathBuf>,
+  uuid: u128,
+  peer_id: u64,
+  account: Account,
+  file_size: u64,
+) -> Result<Reservation, RequestError> {
+  // A stream counts with the bytes it sent so far
+  let bytes = if file_size == UNKNOWN_FILE_SIZE {
+    0
+  } else {
+    file_size
+  };
+  let request = Request {
+    config,
+    base_path,
+    recv_dir,
+    uuid,
+    account: &account,
+    bytes,
+  };
+  LEDGER.reserve(request, peer_id).await
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::config::Quota;
+
+  /// A ledger of its own and an empty app data directory, so tests don't count each other's
+  /// uploads.
+  struct Env {
+    ledger: &'static Ledger,
+    base_path: tempfile::TempDir,
+  }
+
+  impl Env {
+    fn new() -> Self {
+      Self {
+        ledger: Box::leak(Box::default()),
+        base_path: tempfile::tempdir().unwrap(),
+      }
+    }
+
+    async fn reserve(
+      &self,
+      config: &Config,
+      account: &Account,
+      file_size: u64,
+    ) -> Result<Reservation, RequestError> {
+      let request = Request {
+        config,
+        base_path: self.base_path.path(),
+        recv_dir: None,
+        uuid: uuid::Uuid::new_v4().as_u128(),
+        account,
+        bytes: file_size,
+      };
+      self.ledger.reserve(request, u64::MAX).await
+    }
+
+    /// Reserve `count` uploads of `file_size` bytes at once, keeping those which fit.
+    async fn reserve_concurrently(
+      self: &Arc<Self>,
+      config: Config,
+      account: Account,
+      file_size: u64,
+      count: usize,
+    ) -> Vec<Reservation> {
+      let config = Arc::new(config);
+      let handles: Vec<_> = (0..count)
+        .map(|_| {
+          let env = self.clone();
+          let config = config.clone();
+          let account = account.clone();
+          tokio::spawn(async move { env.reserve(&config, &account, file_size).await })
+        })
+        .collect();
+      let mut reservations = vec![];
+      for handle in handles {
+        if let Ok(reservation) = handle.await.unwrap() {
+          reservations.push(reservation);
+        }
+      }
+      reservations
+    }
+  }
+
+  fn quota(client: Option<&str>, token: Option<&str>, bytes: u64) -> Quota {
+    Quota {
+      client: client.map(str::to_string),
+      token: token.map(str::to_string),
+      bytes,
+    }
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn concurrent_requests_share_the_task_limit() {
+    let env = Arc::new(Env::new());
+    let mut config = Config::default();
+    config.limits.max_tasks_per_connection = Some(2);
+    let account = Account::new(None, None);
+
+    let reservations = env
+      .reserve_concurrently(config.clone(), account.clone(), 0, 16)
+      .await;
+    assert_eq!(reservations.len(), 2);
+
+    // Dropped tasks free their slots
+    drop(reservations);
+    let reservations = env.reserve_concurrently(config, account, 0, 16).await;
+    assert_eq!(reservations.len(), 2);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn concurrent_requests_share_the_quota() {
+    let env = Arc::new(Env::new());
+    let mut config = Config::default();
+    config.quotas.push(quota(Some("client"), None, 100));
+    let account = Account::new(Some("client"), None);
+
+    let reservations = env
+      .reserve_concurrently(config.clone(), account.clone(), 40, 16)
+      .await;
+    assert_eq!(reservations.len(), 2);
+
+    drop(reservations);
+    let reservations = env.reserve_concurrently(config, account, 40, 16).await;
+    assert_eq!(reservations.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn token_and_anonymous_uploads_have_quotas() {
+    let env = Env::new();
+    let mut config = Config::default();
+    config.quotas.push(quota(None, Some("token"), 100));
+    // The default of every other upload
+    config.quotas.push(quota(None, None, 50));
+
+    let account = Account::new(None, Some("token"));
+    let _first = env.reserve(&config, &account, 60).await.unwrap();
+    assert_eq!(
+      env.reserve(&config, &account, 60).await.err(),
+      Some(RequestError::QuotaExceeded)
+    );
+
+    let account = Account::new(None, None);
+    let _first = env.reserve(&config, &account, 40).await.unwrap();
+    assert_eq!(
+      env.reserve(&config, &account, 40).await.err(),
+      Some(RequestError::QuotaExceeded)
+    );
+  }
+
+  #[tokio::test]
+  async fn recorded_usage_counts_against_the_quota() {
+    let env = Env::new();
+    let mut config = Config::default();
+    config.quotas.push(quota(Some("client"), None, 100));
+    let account = Account::new(Some("client"), None);
+
+    record_usage(env.base_path.path(), &account, 60)
+      .await
+      .unwrap();
+    assert_eq!(
+      env.reserve(&config, &account, 60).await.err(),
+      Some(RequestError::QuotaExceeded)
+    );
+    env.reserve(&config, &account, 40).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn streams_count_as_they_grow() {
+    let env = Env::new();
+    let mut config = Config::default();
+    config.quotas.push(quota(Some("client"), None, 100));
+    let account = Account::new(Some("client"), None);
+    let base_path = env.base_path.path();
+
+    let mut first = env.reserve(&config, &account, 0).await.unwrap();
+    let mut second = env.reserve(&config, &account, 0).await.unwrap();
+    first.resize(&config, base_path, None, 60).await.unwrap();
+    assert_eq!(
+      second.resize(&config, base_path, None, 60).await.err(),
+      Some(RequestError::QuotaExceeded)
+    );
+    assert_eq!(second.bytes(), 0);
+
+    // A stream over the max file size is rejected even within the quota
+    config.limits.max_file_size = Some(20);
+    assert_eq!(
+      second.resize(&config, base_path, None, 30).await.err(),
+      Some(RequestError::FileTooLarge)
+    );
+  }
+}
@@ -18,6 +18,7 @@ import { filesize } from 'filesize'
 import { useEffect, useState } from 'react'
 import {
   MdCheckCircleOutline,
+  MdErrorOutline,
   MdList,
   MdOpenInNew,
   MdRocketLaunch,
@@ -26,6 +27,17 @@ import { toast } from './common/toast'
 import { CertModal } from './modals/CertModal'
 import { Task } from './types/task'
 
+function getProgress(task: Task) {
+  if (task.blockCount === null) {
+    return 0
+  }
+  // Empty files have no blocks to rebuild
+  if (task.blockCount === 0) {
+    return task.status === 'done' ? 100 : 0
+  }
+  return (task.doneBlockCount / task.blockCount) * 100
+}
+
 function App() {
   const certModal = useDisclosure()
   const [needGenCert, setNeedGenCert] = useState<boolean | null>(null)
@@ -184,14 +196,27 @@ function App() {
                           boxSize="20px"
                           textColor="green.500"
                         />
+                      ) : task.status === 'failed' ? (
+                        <Icon
+                          as={MdErrorOutline}
+                          boxSize="20px"
+                          textColor="red.500"
+                        />
                       ) : (
                         <Spinner size="sm" color="blue.500" />
                       )}
                     </Flex>
                     <Text fontSize={18}>{task.filename}</Text>
                     <Badge colorScheme="blue" variant="outline">
-                      {filesize(task.fileSize, { standard: 'jedec' })}
+                      {task.fileSize === null
+                        ? '流式上传'
+                        : filesize(task.fileSize, { standard: 'jedec' })}
                     </Badge>
+                    {task.encrypted && (
+                      <Badge colorScheme="purple" variant="outline">
+                        端到端加密
+                      </Badge>
+                    )}
                   </Flex>
                   <Flex px={4} py={2} direction="column" gap={1} fontSize={14}>
                     <Flex>
@@ -201,12 +226,29 @@ function App() {
                       {task.status === 'recv' && '接收中'}
                       {task.status === 'merge' && '合并中'}
                       {task.status === 'done' && '已完成'}
+                      {task.status === 'failed' && '保存失败'}
                     </Flex>
+                    {task.tokenName !== null && (
+                      <Flex>
+                        <Box w="90px" textAlign="right" textColor="GrayText">
+                          访问令牌：
+                        </Box>
+                        {task.tokenName}
+                      </Flex>
+                    )}
+                    {task.clientIdentity !== null && (
+                      <Flex>
+                        <Box w="90px" textAlign="right" textColor="GrayText">
+                          客户端身份：
+                        </Box>
+                        {task.clientIdentity}
+                      </Flex>
+                    )}
                     <Flex>
                       <Box w="90px" textAlign="right" textColor="GrayText">
                         块总数：
                       </Box>
-                      {task.blockCount}
+                      {task.blockCount ?? '未知'}
                     </Flex>
                     <Flex>
                       <Box w="90px" textAlign="right" textColor="GrayText">
@@ -218,16 +260,45 @@ function App() {
                       <Box w="90px" textAlign="right" textColor="GrayText">
                         重建进度：
                       </Box>
-                      {((task.doneBlockCount / task.blockCount) * 100).toFixed(
-                        1,
-                      )}
+                      {getProgress(task).toFixed(1)}
                       %
                     </Flex>
+                    {task.hooks.map((hook) => (
+                      <Flex key={hook.name}>
+                        <Box
+                          w="90px"
+                          flexShrink={0}
+                          textAlign="right"
+                          textColor="GrayText"
+                        >
+                          钩子：
+                        </Box>
+                        <Flex direction="column">
+                          <Text
+                            textColor={hook.success ? 'green.500' : 'red.500'}
+                          >
+                            {hook.name}{' '}
+                            {hook.success ? '成功' : '失败'}
+                            {hook.exitCode !== null &&
+                              `（退出码 ${hook.exitCode}）`}
+                          </Text>
+                          {hook.output && (
+                            <Text
+                              fontSize={12}
+                              fontFamily="mono"
+                              whiteSpace="pre-wrap"
+                            >
+                              {hook.output}
+                            </Text>
+                          )}
+                        </Flex>
+                      </Flex>
+                    ))}
 
                     <Progress
                       my={2}
                       size="sm"
-                      value={(task.doneBlockCount / task.blockCount) * 100}
+                      value={getProgress(task)}
                     />
                   </Flex>
                 </Card>
@@ -12,6 +12,7 @@ import {
   ModalFooter,
   ModalHeader,
   ModalOverlay,
+  Select,
 } from '@chakra-ui/react'
 import { invoke, shell } from '@tauri-apps/api'
 import { appDataDir, resolve } from '@tauri-apps/api/path'
@@ -19,6 +20,12 @@ import { Dispatch, SetStateAction, useEffect, useState } from 'react'
 import { MdLock } from 'react-icons/md'
 import { toast } from '../common/toast'
 
+interface CertInfo {
+  fingerprint: string
+  previousFingerprint: string | null
+  previousUntil: number | null
+}
+
 export function CertModal({
   isOpen,
   onClose,
@@ -32,6 +39,11 @@ export function CertModal({
 }) {
   const [certPath, setCertPath] = useState('')
   const [keyPath, setKeyPath] = useState('')
+  const [certInfo, setCertInfo] = useState<CertInfo | null>(null)
+  const [names, setNames] = useState('qft-server')
+  const [keyAlgorithm, setKeyAlgorithm] = useState('ecdsa-p256')
+  const [validityDays, setValidityDays] = useState(0)
+  const [rotationDays, setRotationDays] = useState(7)
 
   useEffect(() => {
     if (needGenCert !== false) {
@@ -41,11 +53,27 @@ export function CertModal({
       const appDataPath = await appDataDir()
       setCertPath(await resolve(appDataPath, 'cert', 'cert.der'))
       setKeyPath(await resolve(appDataPath, 'cert', 'key.der'))
+      setCertInfo(await invoke<CertInfo>('get_cert_info'))
     })()
   }, [needGenCert])
 
   async function handleGenCert() {
-    await invoke('gen_cert')
+    try {
+      setCertInfo(
+        await invoke<CertInfo>('gen_cert', {
+          names: names
+            .split(',')
+            .map((x) => x.trim())
+            .filter((x) => x !== ''),
+          keyAlgorithm,
+          validityDays: validityDays > 0 ? validityDays : null,
+          rotationDays,
+        }),
+      )
+    } catch (e) {
+      toast({ title: `生成失败：${e}`, status: 'error' })
+      return
+    }
     toast({
       title: '生成成功',
       status: 'success',
@@ -104,6 +132,25 @@ export function CertModal({
                   证书私钥位置
                   <Input mt={1} type="text" value={keyPath} isReadOnly />
                 </div>
+                <div>
+                  证书指纹（SHA-256）
+                  <Input
+                    mt={1}
+                    type="text"
+                    fontSize={12}
+                    value={certInfo?.fingerprint ?? ''}
+                    isReadOnly
+                  />
+                </div>
+                {certInfo?.previousFingerprint && certInfo.previousUntil && (
+                  <Alert status="warning" textAlign="justify" fontSize={14}>
+                    证书轮换中，
+                    {new Date(certInfo.previousUntil * 1000).toLocaleString()}
+                    前仍使用旧证书（{certInfo.previousFingerprint}
+                    ），请在此之前将新证书分发至客户端，与旧证书一并信任（如
+                    cert/next.der，或在 known_hosts.json 中添加新指纹）
+                  </Alert>
+                )}
               </Flex>
               {needGenCert && (
                 <Flex
@@ -123,6 +170,70 @@ export function CertModal({
                 </Flex>
               )}
             </Box>
+
+            <Flex direction="column" gap={2}>
+              <div>
+                证书名称（主机名或 IP，以逗号分隔）
+                <Input
+                  mt={1}
+                  type="text"
+                  value={names}
+                  onChange={(e) => setNames(e.target.value)}
+                />
+              </div>
+              <div>
+                密钥算法
+                <Select
+                  mt={1}
+                  value={keyAlgorithm}
+                  onChange={(e) => setKeyAlgorithm(e.target.value)}
+                >
+                  <option value="ecdsa-p256">ECDSA P-256</option>
+                  <option value="ecdsa-p384">ECDSA P-384</option>
+                  <option value="ed25519">Ed25519</option>
+                </Select>
+              </div>
+              <div>
+                有效期
+                <Select
+                  mt={1}
+                  value={validityDays}
+                  onChange={(e) => setValidityDays(Number(e.target.value))}
+                >
+                  <option value={0}>永久</option>
+                  <option value={90}>90 天</option>
+                  <option value={365}>1 年</option>
+                  <option value={3650}>10 年</option>
+                </Select>
+              </div>
+              {!needGenCert && (
+                <div>
+                  轮换期（期间继续使用旧证书）
+                  <Select
+                    mt={1}
+                    value={rotationDays}
+                    onChange={(e) => setRotationDays(Number(e.target.value))}
+                  >
+                    <option value={0}>立即替换</option>
+                    <option value={1}>1 天</option>
+                    <option value={7}>7 天</option>
+                    <option value={30}>30 天</option>
+                  </Select>
+                </div>
+              )}
+              {!needGenCert && (
+                <Flex justifyContent="end">
+                  <Button
+                    colorScheme="red"
+                    variant="outline"
+                    size="sm"
+                    onClick={handleGenCert}
+                  >
+                    轮换证书
+                  </Button>
+                </Flex>
+              )}
+            </Flex>
           </ModalBody>
 
           <ModalFooter>
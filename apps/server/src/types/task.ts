@@ -1,8 +1,20 @@
 export interface Task {
   filename: string
-  fileSize: number
+  // null while a streamed upload hasn't announced its size
+  fileSize: number | null
   uuid: string
-  blockCount: number
+  blockCount: number | null
   doneBlockCount: number
-  status: 'recv' | 'merge' | 'done'
+  status: 'recv' | 'merge' | 'done' | 'failed'
+  tokenName: string | null
+  clientIdentity: string | null
+  encrypted: boolean
+  hooks: HookResult[]
+}
+
+export interface HookResult {
+  name: string
+  success: boolean
+  exitCode: number | null
+  output: string
 }
@@ -0,0 +1,114 @@
+//! Server side decode throughput with several clients uploading at once. Compares decoding under
+//! one global lock against the decode pool.
+//!
+//! cargo bench --bench decode
+
+use std::{sync::Arc, time::Instant};
+
+use rust_common::{
+  erasure::{decode_block, encode_block},
+  params::TransferParams,
+  pipeline::DecodePool,
+};
+use tokio::{
+  fs::{self, File},
+  sync::Mutex,
+};
+
+const BLOCKS_PER_CLIENT: u32 = 16;
+const CLIENTS: [u32; 4] = [1, 2, 4, 8];
+
+#[derive(Clone, Copy)]
+enum Mode {
+  GlobalLock,
+  Pool,
+}
+
+#[tokio::main]
+async fn main() {
+  let params = TransferParams::default();
+  let file_size = params.block_size * BLOCKS_PER_CLIENT as u64;
+
+  let dir = std::env::temp_dir().join(format!("qft-bench-decode-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let source_path = dir.join("source");
+  let data: Vec<u8> = (0..file_size).map(|i| (i * 31 % 251) as u8).collect();
+  fs::write(&source_path, &data).await.unwrap();
+
+  let file = File::open(&source_path).await.unwrap();
+  let mut blocks = vec![];
+  for block_id in 0..BLOCKS_PER_CLIENT {
+    let encoded = encode_block(&file, block_id, 0.1, &params, None)
+      .await
+      .unwrap();
+    // Lose the first few packets, so repair symbols are needed
+    let packets: Vec<bytes::Bytes> = encoded
+      .packets
+      .into_iter()
+      .skip(8)
+      .map(Into::into)
+      .collect();
+    blocks.push((encoded.encoded_len as u64, packets));
+  }
+  let blocks = Arc::new(blocks);
+
+  let tmp_base_path = dir.join("tmp").to_string_lossy().to_string();
+  let lock = Arc::new(Mutex::new(()));
+  let pool = Arc::new(DecodePool::default());
+
+  println!("clients  global lock (MiB/s)  decode pool (MiB/s)");
+  for clients in CLIENTS {
+    let mut speeds = vec![];
+    for mode in [Mode::GlobalLock, Mode::Pool] {
+      let start = Instant::now();
+
+      let mut handles = vec![];
+      for uuid in 0..clients as u128 {
+        let blocks = blocks.clone();
+        let lock = lock.clone();
+        let pool = pool.clone();
+        let tmp_base_path = tmp_base_path.clone();
+        handles.push(tokio::spawn(async move {
+          let mut block_handles = vec![];
+          for block_id in 0..BLOCKS_PER_CLIENT {
+            let (encoded_len, packets) = blocks[block_id as usize].clone();
+            let lock = lock.clone();
+            let pool = pool.clone();
+            let tmp_base_path = tmp_base_path.clone();
+            block_handles.push(tokio::spawn(async move {
+              let decode = decode_block(
+                uuid,
+                block_id,
+                file_size,
+                encoded_len,
+                &params,
+                packets,
+                &tmp_base_path,
+              );
+              let result = match mode {
+                Mode::GlobalLock => {
+                  let _guard = lock.lock().await;
+                  decode.await
+                }
+                Mode::Pool => pool.run(decode).await,
+              };
+              result.map_err(|e| e.to_string()).unwrap();
+            }));
+          }
+          for handle in block_handles {
+            handle.await.unwrap();
+          }
+        }));
+      }
+      for handle in handles {
+        handle.await.unwrap();
+      }
+
+      let elapsed = start.elapsed().as_secs_f64();
+      speeds.push((file_size * clients as u64) as f64 / 1024.0 / 1024.0 / elapsed);
+    }
+    println!("{:>7}  {:>19.2}  {:>19.2}", clients, speeds[0], speeds[1]);
+  }
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
@@ -0,0 +1,43 @@
+use std::{env, path::Path, process};
+
+use rust_common::crypto::{self, Secret};
+
+const USAGE: &str = "usage:
+  qft-decrypt keygen
+  qft-decrypt <input> <output> --passphrase <passphrase>
+  qft-decrypt <input> <output> --key <hex secret key>
+
+The passphrase may also be set with the QFT_PASSPHRASE environment variable.";
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+
+  let result = match args.as_slice() {
+    [command] if command == "keygen" => {
+      let (secret, public) = crypto::generate_key_pair();
+      println!("secret key: {}", crypto::encode_key(&secret));
+      println!("public key: {}", crypto::encode_key(&public));
+      Ok(())
+    }
+    [input, output, option, value] if option == "--passphrase" => {
+      decrypt(input, output, Secret::Passphrase(value.to_string()))
+    }
+    [input, output, option, value] if option == "--key" => {
+      crypto::decode_key(value).and_then(|key| decrypt(input, output, Secret::SecretKey(key)))
+    }
+    [input, output] => match env::var("QFT_PASSPHRASE") {
+      Ok(passphrase) => decrypt(input, output, Secret::Passphrase(passphrase)),
+      Err(_) => Err(USAGE.to_string()),
+    },
+    _ => Err(USAGE.to_string()),
+  };
+
+  if let Err(e) = result {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+}
+
+fn decrypt(input: &str, output: &str, secret: Secret) -> Result<(), String> {
+  crypto::decrypt_file(Path::new(input), Path::new(output), &secret).map_err(|e| e.to_string())
+}
@@ -0,0 +1,105 @@
+mod plain;
+mod raptorq;
+mod reed_solomon;
+
+use std::{fmt, str::FromStr};
+
+pub use self::{plain::Plain, raptorq::RaptorQ, reed_solomon::ReedSolomon};
+use crate::params::TransferParams;
+
+/// Symbol sizes must be a multiple of the alignment.
+pub const ALIGNMENT: u8 = 8;
+
+/// Length of the packet ID preceding each symbol. Every codec emits packets of exactly
+/// `PAYLOAD_ID_LENGTH + symbol_size` bytes.
+pub const PAYLOAD_ID_LENGTH: usize = 4;
+
+/// Forward error correction applied to each block.
+pub trait ErasureCodec: Send + Sync {
+  /// Check codec specific limits of the params.
+  fn validate(&self, params: &TransferParams) -> Result<(), String> {
+    if params.source_blocks != 1 {
+      return Err("only RaptorQ supports multiple source blocks".into());
+    }
+    Ok(())
+  }
+
+  /// Encode the real bytes of a block into packets. Source packets come first.
+  fn encode(&self, params: &TransferParams, data: &[u8], parity_rate: f32) -> Vec<Vec<u8>>;
+
+  /// Rebuild a block of `block_len` bytes. Returns `None` if the packets are not enough yet.
+  fn decode(
+    &self,
+    params: &TransferParams,
+    block_len: u64,
+    packets: Vec<bytes::Bytes>,
+  ) -> Option<Vec<u8>>;
+}
+
+/// Codec of a transfer, sent as u8 in the upload request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CodecKind {
+  #[default]
+  RaptorQ,
+  ReedSolomon,
+  /// No FEC. Lost packets are recovered by retransmitting the whole block.
+  Plain,
+}
+
+impl CodecKind {
+  pub fn codec(&self) -> &'static dyn ErasureCodec {
+    match self {
+      Self::RaptorQ => &RaptorQ,
+      Self::ReedSolomon => &ReedSolomon,
+      Self::Plain => &Plain,
+    }
+  }
+
+  pub fn id(&self) -> u8 {
+    match self {
+      Self::RaptorQ => 0,
+      Self::ReedSolomon => 1,
+      Self::Plain => 2,
+    }
+  }
+
+  pub fn from_id(id: u8) -> Option<Self> {
+    match id {
+      0 => Some(Self::RaptorQ),
+      1 => Some(Self::ReedSolomon),
+      2 => Some(Self::Plain),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for CodecKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::RaptorQ => write!(f, "raptorq"),
+      Self::ReedSolomon => write!(f, "reed-solomon"),
+      Self::Plain => write!(f, "plain"),
+    }
+  }
+}
+
+impl FromStr for CodecKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "raptorq" => Ok(Self::RaptorQ),
+      "reed-solomon" => Ok(Self::ReedSolomon),
+      "plain" => Ok(Self::Plain),
+      _ => Err(format!("unknown codec {}", s)),
+    }
+  }
+}
+
+/// Pad the data with zeros to whole symbols.
+fn pad_to_symbols(data: &[u8], symbol_size: u16) -> Vec<u8> {
+  let symbols = data.len().div_ceil(symbol_size as usize);
+  let mut padded = data.to_vec();
+  padded.resize(symbols * symbol_size as usize, 0);
+  padded
+}
@@ -0,0 +1,50 @@
+use super::{pad_to_symbols, ErasureCodec, PAYLOAD_ID_LENGTH};
+use crate::params::TransferParams;
+
+/// Sends the source symbols only, each prefixed with its u32 index.
+pub struct Plain;
+
+impl ErasureCodec for Plain {
+  fn encode(&self, params: &TransferParams, data: &[u8], _parity_rate: f32) -> Vec<Vec<u8>> {
+    pad_to_symbols(data, params.symbol_size)
+      .chunks(params.symbol_size as usize)
+      .enumerate()
+      .map(|(i, symbol)| {
+        let mut packet = (i as u32).to_be_bytes().to_vec();
+        packet.extend_from_slice(symbol);
+        packet
+      })
+      .collect()
+  }
+
+  fn decode(
+    &self,
+    params: &TransferParams,
+    block_len: u64,
+    packets: Vec<bytes::Bytes>,
+  ) -> Option<Vec<u8>> {
+    let symbol_size = params.symbol_size as usize;
+    let source_symbols = (block_len as usize).div_ceil(symbol_size);
+
+    let mut data = vec![0; source_symbols * symbol_size];
+    let mut received = vec![false; source_symbols];
+    for packet in packets {
+      if packet.len() != PAYLOAD_ID_LENGTH + symbol_size {
+        continue;
+      }
+      let index = u32::from_be_bytes(packet[..PAYLOAD_ID_LENGTH].try_into().unwrap()) as usize;
+      if index < source_symbols {
+        data[index * symbol_size..(index + 1) * symbol_size]
+          .copy_from_slice(&packet[PAYLOAD_ID_LENGTH..]);
+        received[index] = true;
+      }
+    }
+
+    if received.contains(&false) {
+      return None;
+    }
+
+    data.truncate(block_len as usize);
+    Some(data)
+  }
+}
@@ -0,0 +1,234 @@
+use std::{
+  iter,
+  sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use raptorq::{
+  calculate_block_offsets, Decoder, EncodingPacket, ObjectTransmissionInformation,
+  SourceBlockDecoder, SourceBlockEncoder, SourceBlockEncodingPlan,
+};
+
+use super::{pad_to_symbols, ErasureCodec, ALIGNMENT};
+use crate::params::TransferParams;
+
+pub const SOURCE_BLOCKS: u8 = 1;
+pub const SUB_BLOCKS: u16 = 1;
+
+/// Upper bound of source symbols in a single RaptorQ source block (K'max, RFC 6330).
+pub const MAX_SOURCE_SYMBOLS_PER_BLOCK: u64 = 56403;
+
+/// Plans kept at once. A plan of a large block takes megabytes, while a client only uses the
+/// full block sizes of its few transfers in flight.
+const MAX_ENCODE_PLANS: usize = 4;
+
+/// Plans by source symbol count.
+type EncodePlans = Vec<(u32, Arc<SourceBlockEncodingPlan>)>;
+
+/// Recently used plans, the most recent last.
+static ENCODE_PLANS: Lazy<Mutex<EncodePlans>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Config of a block encoded as a single source block. Cheap to build, so it isn't cached.
+fn encode_config(params: &TransferParams) -> ObjectTransmissionInformation {
+  ObjectTransmissionInformation::new(
+    params.encoded_block_size(),
+    params.symbol_size,
+    SOURCE_BLOCKS,
+    SUB_BLOCKS,
+    ALIGNMENT,
+  )
+}
+
+/// Find a cached plan, and mark it as the most recently used.
+fn cached_plan(
+  plans: &mut EncodePlans,
+  source_symbols: u32,
+) -> Option<Arc<SourceBlockEncodingPlan>> {
+  let index = plans.iter().position(|(x, _)| *x == source_symbols)?;
+  let entry = plans.remove(index);
+  let plan = entry.1.clone();
+  plans.push(entry);
+  Some(plan)
+}
+
+/// Plans only depend on the symbol count, so they are shared by every parameter set
+/// with the same `block_size / symbol_size`. Only used for full blocks, and only the most
+/// recently used plans are kept.
+fn encode_plan(source_symbols: u32) -> Arc<SourceBlockEncodingPlan> {
+  if let Some(plan) = cached_plan(&mut ENCODE_PLANS.lock().unwrap(), source_symbols) {
+    return plan;
+  }
+
+  // Generating a plan is expensive, don't hold the lock meanwhile.
+  let plan = Arc::new(SourceBlockEncodingPlan::generate(source_symbols as u16));
+  let mut plans = ENCODE_PLANS.lock().unwrap();
+  if let Some(plan) = cached_plan(&mut plans, source_symbols) {
+    return plan;
+  }
+  if plans.len() == MAX_ENCODE_PLANS {
+    plans.remove(0);
+  }
+  plans.push((source_symbols, plan.clone()));
+  plan
+}
+
+/// Encoder of a source block. A plan only pays off when it's reused, so it's only used for full
+/// blocks. Compressed and last blocks have nearly unique symbol counts and are encoded without.
+fn block_encoder(
+  sbn: u8,
+  config: &ObjectTransmissionInformation,
+  block: &[u8],
+  symbol_size: u16,
+  full_block: bool,
+) -> SourceBlockEncoder {
+  if !full_block {
+    return SourceBlockEncoder::new(sbn, config, block);
+  }
+  let plan = encode_plan((block.len() / symbol_size as usize) as u32);
+  SourceBlockEncoder::with_encoding_plan(sbn, config, block, &plan)
+}
+
+/// Config of a block encoded as an object of several source blocks, derived from its real
+/// length so both sides agree on the partitioning.
+fn object_config(params: &TransferParams, block_len: u64) -> ObjectTransmissionInformation {
+  match params.source_blocks {
+    0 => ObjectTransmissionInformation::with_defaults(block_len, params.symbol_size),
+    source_blocks => {
+      // A short last block may have fewer symbols than source blocks
+      let symbols = block_len.div_ceil(params.symbol_size as u64);
+      ObjectTransmissionInformation::new(
+        block_len,
+        params.symbol_size,
+        (source_blocks as u64).min(symbols) as u8,
+        SUB_BLOCKS,
+        ALIGNMENT,
+      )
+    }
+  }
+}
+
+/// Encode every source block of the object, then interleave the packets across source blocks so
+/// a loss burst is spread over all of them.
+fn encode_object(params: &TransferParams, data: &[u8], parity_rate: f32) -> Vec<Vec<u8>> {
+  let config = object_config(params, data.len() as u64);
+  let full_block = data.len() as u64 == params.encoded_block_size();
+
+  let mut source_packets = vec![];
+  let mut repair_packets = vec![];
+  for (sbn, (start, end)) in calculate_block_offsets(data, &config)
+    .into_iter()
+    .enumerate()
+  {
+    let block = pad_to_symbols(&data[start..end.min(data.len())], params.symbol_size);
+    let source_symbols = (block.len() / params.symbol_size as usize) as u32;
+    let parity = (parity_rate * source_symbols as f32).ceil() as u32;
+
+    let block_encoder = block_encoder(sbn as u8, &config, &block, params.symbol_size, full_block);
+    source_packets.push(block_encoder.source_packets());
+    repair_packets.push(block_encoder.repair_packets(0, parity));
+  }
+
+  let mut packets = interleave(source_packets);
+  packets.extend(interleave(repair_packets));
+  packets.into_iter().map(|x| x.serialize()).collect()
+}
+
+fn interleave(lists: Vec<Vec<EncodingPacket>>) -> Vec<EncodingPacket> {
+  let mut iters: Vec<_> = lists.into_iter().map(|x| x.into_iter()).collect();
+  let mut result = vec![];
+  loop {
+    let len = result.len();
+    result.extend(iters.iter_mut().filter_map(|x| x.next()));
+    if result.len() == len {
+      return result;
+    }
+  }
+}
+
+fn decode_object(
+  params: &TransferParams,
+  block_len: u64,
+  packets: Vec<bytes::Bytes>,
+) -> Option<Vec<u8>> {
+  let config = object_config(params, block_len);
+  let mut decoder = Decoder::new(config);
+
+  for packet in packets {
+    let packet = EncodingPacket::deserialize(&packet);
+    // Decoder panics on unknown source blocks
+    if packet.payload_id().source_block_number() >= config.source_blocks() {
+      continue;
+    }
+    if let Some(decoded) = decoder.decode(packet) {
+      return Some(decoded);
+    }
+  }
+  None
+}
+
+pub struct RaptorQ;
+
+impl ErasureCodec for RaptorQ {
+  fn validate(&self, params: &TransferParams) -> Result<(), String> {
+    // Partitioning derived from the length always fits
+    if params.source_blocks == 0 {
+      return Ok(());
+    }
+
+    let symbols_per_block = params
+      .source_symbols()
+      .div_ceil(params.source_blocks as u32);
+    if symbols_per_block as u64 > MAX_SOURCE_SYMBOLS_PER_BLOCK {
+      return Err(format!(
+        "{} source symbols per source block exceeds {}",
+        symbols_per_block, MAX_SOURCE_SYMBOLS_PER_BLOCK
+      ));
+    }
+    Ok(())
+  }
+
+  fn encode(&self, params: &TransferParams, data: &[u8], parity_rate: f32) -> Vec<Vec<u8>> {
+    if params.source_blocks != 1 {
+      return encode_object(params, data, parity_rate);
+    }
+
+    let full_block = data.len() as u64 == params.encoded_block_size();
+    let data = pad_to_symbols(data, params.symbol_size);
+    let source_symbols = (data.len() / params.symbol_size as usize) as u32;
+    let parity_per_block = (parity_rate * source_symbols as f32).ceil() as u32;
+
+    let config = encode_config(params);
+    let block_encoder = block_encoder(0, &config, &data, params.symbol_size, full_block);
+    let mut packets = block_encoder.source_packets();
+    packets.extend(block_encoder.repair_packets(0, parity_per_block));
+    packets.into_iter().map(|x| x.serialize()).collect()
+  }
+
+  fn decode(
+    &self,
+    params: &TransferParams,
+    block_len: u64,
+    mut packets: Vec<bytes::Bytes>,
+  ) -> Option<Vec<u8>> {
+    if params.source_blocks != 1 {
+      return decode_object(params, block_len, packets);
+    }
+
+    let config = encode_config(params);
+    let mut block_decoder = SourceBlockDecoder::new(0, &config, block_len);
+
+    while let Some(packet) = packets.pop() {
+      let packet = EncodingPacket::deserialize(&packet);
+      // SourceBlockDecoder panics on packets of another source block
+      if packet.payload_id().source_block_number() != 0 {
+        continue;
+      }
+      let result = block_decoder.decode(iter::once(packet));
+      if let Some(mut decoded) = result {
+        decoded.truncate(block_len as usize);
+        return Some(decoded);
+      }
+    }
+    None
+  }
+}
@@ -0,0 +1,152 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use reed_solomon_erasure::galois_8;
+
+use super::{pad_to_symbols, ErasureCodec, PAYLOAD_ID_LENGTH};
+use crate::params::TransferParams;
+
+/// GF(2^8) limits data and parity shards of one stripe to 256 in total.
+const MAX_SHARDS: usize = 256;
+/// Large blocks are split into interleaved stripes of at most this many data shards.
+const MAX_DATA_SHARDS: usize = 128;
+
+/// Coders by data and parity shard count
+type Coders = HashMap<(usize, usize), Arc<galois_8::ReedSolomon>>;
+
+static CODERS: Lazy<Mutex<Coders>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn coder(data_shards: usize, parity_shards: usize) -> Arc<galois_8::ReedSolomon> {
+  CODERS
+    .lock()
+    .unwrap()
+    .entry((data_shards, parity_shards))
+    .or_insert_with(|| Arc::new(galois_8::ReedSolomon::new(data_shards, parity_shards).unwrap()))
+    .clone()
+}
+
+/// Layout of a block: data shard `i` belongs to stripe `i % stripes`, parity shard `j` of
+/// stripe `s` gets packet index `data_shards + j * stripes + s`. Parity rows of a Vandermonde
+/// based coder don't depend on the parity count, so the decoder doesn't need to know it.
+struct Layout {
+  data_shards: usize,
+  stripes: usize,
+}
+
+impl Layout {
+  fn new(data_shards: usize) -> Self {
+    Self {
+      data_shards,
+      stripes: data_shards.div_ceil(MAX_DATA_SHARDS),
+    }
+  }
+
+  fn stripe_data_shards(&self, stripe: usize) -> usize {
+    (self.data_shards - stripe).div_ceil(self.stripes)
+  }
+}
+
+/// Reed-Solomon erasure coding over GF(2^8). Cheaper than RaptorQ for blocks with few symbols.
+pub struct ReedSolomon;
+
+impl ErasureCodec for ReedSolomon {
+  fn encode(&self, params: &TransferParams, data: &[u8], parity_rate: f32) -> Vec<Vec<u8>> {
+    let symbol_size = params.symbol_size as usize;
+    let data = pad_to_symbols(data, params.symbol_size);
+    let layout = Layout::new(data.len() / symbol_size);
+
+    let max_stripe_shards = layout.stripe_data_shards(0);
+    let parity_shards = ((parity_rate * max_stripe_shards as f32).ceil() as usize)
+      .min(MAX_SHARDS - max_stripe_shards);
+
+    let mut packets: Vec<Vec<u8>> = data
+      .chunks(symbol_size)
+      .enumerate()
+      .map(|(i, symbol)| {
+        let mut packet = (i as u32).to_be_bytes().to_vec();
+        packet.extend_from_slice(symbol);
+        packet
+      })
+      .collect();
+
+    if parity_shards == 0 {
+      return packets;
+    }
+
+    let mut parity_packets = vec![vec![]; parity_shards * layout.stripes];
+    for stripe in 0..layout.stripes {
+      let mut shards: Vec<Vec<u8>> = (stripe..layout.data_shards)
+        .step_by(layout.stripes)
+        .map(|i| packets[i][PAYLOAD_ID_LENGTH..].to_vec())
+        .collect();
+      let data_shards = shards.len();
+      shards.resize(data_shards + parity_shards, vec![0; symbol_size]);
+
+      coder(data_shards, parity_shards)
+        .encode(&mut shards)
+        .unwrap();
+
+      for (j, shard) in shards.drain(data_shards..).enumerate() {
+        let index = j * layout.stripes + stripe;
+        let mut packet = ((layout.data_shards + index) as u32).to_be_bytes().to_vec();
+        packet.extend(shard);
+        parity_packets[index] = packet;
+      }
+    }
+
+    packets.extend(parity_packets);
+    packets
+  }
+
+  fn decode(
+    &self,
+    params: &TransferParams,
+    block_len: u64,
+    packets: Vec<bytes::Bytes>,
+  ) -> Option<Vec<u8>> {
+    let symbol_size = params.symbol_size as usize;
+    let layout = Layout::new((block_len as usize).div_ceil(symbol_size));
+
+    // Shards of every stripe, data shards first and then all possible parity shards
+    let mut stripes: Vec<Vec<Option<Vec<u8>>>> = vec![vec![None; MAX_SHARDS]; layout.stripes];
+    for packet in packets {
+      if packet.len() != PAYLOAD_ID_LENGTH + symbol_size {
+        continue;
+      }
+      let index = u32::from_be_bytes(packet[..PAYLOAD_ID_LENGTH].try_into().unwrap()) as usize;
+      let (stripe, shard) = if index < layout.data_shards {
+        (index % layout.stripes, index / layout.stripes)
+      } else {
+        let index = index - layout.data_shards;
+        let stripe = index % layout.stripes;
+        (
+          stripe,
+          layout.stripe_data_shards(stripe) + index / layout.stripes,
+        )
+      };
+      if shard < MAX_SHARDS {
+        stripes[stripe][shard] = Some(packet[PAYLOAD_ID_LENGTH..].to_vec());
+      }
+    }
+
+    for (stripe, shards) in stripes.iter_mut().enumerate() {
+      let data_shards = layout.stripe_data_shards(stripe);
+      if shards.iter().flatten().count() < data_shards {
+        return None;
+      }
+      coder(data_shards, MAX_SHARDS - data_shards)
+        .reconstruct_data(shards)
+        .ok()?;
+    }
+
+    let mut data = Vec::with_capacity(layout.data_shards * symbol_size);
+    for i in 0..layout.data_shards {
+      data.extend(stripes[i % layout.stripes][i / layout.stripes].as_ref()?);
+    }
+    data.truncate(block_len as usize);
+    Some(data)
+  }
+}
@@ -0,0 +1,86 @@
+use std::{fmt, io, str::FromStr};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression applied to each block before encoding, sent as u8 in the upload request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Compression {
+  #[default]
+  None,
+  Zstd,
+  Lz4,
+}
+
+impl Compression {
+  pub fn id(&self) -> u8 {
+    match self {
+      Self::None => 0,
+      Self::Zstd => 1,
+      Self::Lz4 => 2,
+    }
+  }
+
+  pub fn from_id(id: u8) -> Option<Self> {
+    match id {
+      0 => Some(Self::None),
+      1 => Some(Self::Zstd),
+      2 => Some(Self::Lz4),
+      _ => None,
+    }
+  }
+
+  /// Compress a block. Returns `None` if it doesn't shrink, so the block is sent as is.
+  pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = match self {
+      Self::None => return None,
+      Self::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok()?,
+      Self::Lz4 => lz4_flex::block::compress(data),
+    };
+    (compressed.len() < data.len()).then_some(compressed)
+  }
+
+  /// Decompress a block of exactly `block_len` bytes.
+  pub fn decompress(&self, data: &[u8], block_len: usize) -> io::Result<Vec<u8>> {
+    let decompressed = match self {
+      Self::None => Ok(data.to_vec()),
+      Self::Zstd => zstd::bulk::decompress(data, block_len),
+      Self::Lz4 => lz4_flex::block::decompress(data, block_len)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }?;
+
+    if decompressed.len() != block_len {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "decompressed {} bytes, expected {}",
+          decompressed.len(),
+          block_len
+        ),
+      ));
+    }
+    Ok(decompressed)
+  }
+}
+
+impl fmt::Display for Compression {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::None => write!(f, "none"),
+      Self::Zstd => write!(f, "zstd"),
+      Self::Lz4 => write!(f, "lz4"),
+    }
+  }
+}
+
+impl FromStr for Compression {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(Self::None),
+      "zstd" => Ok(Self::Zstd),
+      "lz4" => Ok(Self::Lz4),
+      _ => Err(format!("unknown compression {}", s)),
+    }
+  }
+}
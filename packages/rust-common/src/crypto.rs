@@ -0,0 +1,330 @@
+use std::{
+  fs::File,
+  io::{self, BufReader, BufWriter, Read, Write},
+  num::NonZeroU32,
+  path::Path,
+};
+
+use ring::{
+  aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+  hkdf, pbkdf2,
+  rand::{SecureRandom, SystemRandom},
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::params::{MAX_PARTITIONED_BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+/// Authentication tag appended to each encrypted block.
+pub const TAG_LEN: u64 = 16;
+
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+/// Headers asking for more iterations are rejected, so a crafted file can't hang the decryptor.
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// Appended to the name of a received file which is still encrypted.
+pub const ENCRYPTED_EXTENSION: &str = "qftenc";
+
+const MAGIC: &[u8; 4] = b"QFTE";
+const VERSION: u8 = 1;
+const KDF_PASSPHRASE: u8 = 1;
+const KDF_X25519: u8 = 2;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const HKDF_INFO: &[u8] = b"qft block key";
+
+/// Who can decrypt a transfer.
+pub enum Recipient {
+  Passphrase(String),
+  /// X25519 public key of the recipient.
+  PublicKey([u8; KEY_LEN]),
+}
+
+/// What decrypts a transfer.
+pub enum Secret {
+  Passphrase(String),
+  /// X25519 secret key of the recipient.
+  SecretKey([u8; KEY_LEN]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kdf {
+  Passphrase {
+    iterations: u32,
+    salt: [u8; SALT_LEN],
+  },
+  X25519 {
+    ephemeral: [u8; KEY_LEN],
+  },
+}
+
+/// Stored in front of an encrypted file, and authenticated with every block.
+///
+/// "QFTE", u8 version, u8 KDF, u64 file size, u64 block size, then for a passphrase the u32
+/// PBKDF2 iterations and 16 byte salt, or for a recipient key the 32 byte ephemeral X25519 key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncryptionHeader {
+  pub file_size: u64,
+  pub block_size: u64,
+  kdf: Kdf,
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl EncryptionHeader {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    match self.kdf {
+      Kdf::Passphrase { .. } => bytes.push(KDF_PASSPHRASE),
+      Kdf::X25519 { .. } => bytes.push(KDF_X25519),
+    }
+    bytes.extend(self.file_size.to_be_bytes());
+    bytes.extend(self.block_size.to_be_bytes());
+    match self.kdf {
+      Kdf::Passphrase { iterations, salt } => {
+        bytes.extend(iterations.to_be_bytes());
+        bytes.extend(salt);
+      }
+      Kdf::X25519 { ephemeral } => bytes.extend(ephemeral),
+    }
+    bytes
+  }
+
+  pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+    let mut magic = [0; 6];
+    reader.read_exact(&mut magic)?;
+    if &magic[..4] != MAGIC || magic[4] != VERSION {
+      return Err(invalid_data("not an encrypted QFT file"));
+    }
+
+    let mut sizes = [0; 16];
+    reader.read_exact(&mut sizes)?;
+    let file_size = u64::from_be_bytes(sizes[..8].try_into().unwrap());
+    let block_size = u64::from_be_bytes(sizes[8..].try_into().unwrap());
+    // Blocks are allocated whole and numbered by u32
+    if !(MIN_BLOCK_SIZE..=MAX_PARTITIONED_BLOCK_SIZE).contains(&block_size) {
+      return Err(invalid_data(format!(
+        "block size {} out of range [{}, {}]",
+        block_size, MIN_BLOCK_SIZE, MAX_PARTITIONED_BLOCK_SIZE
+      )));
+    }
+    if file_size.div_ceil(block_size) > u32::MAX as u64 {
+      return Err(invalid_data("too many blocks"));
+    }
+
+    let kdf = match magic[5] {
+      KDF_PASSPHRASE => {
+        let mut iterations = [0; 4];
+        let mut salt = [0; SALT_LEN];
+        reader.read_exact(&mut iterations)?;
+        reader.read_exact(&mut salt)?;
+        let iterations = u32::from_be_bytes(iterations);
+        if !(1..=MAX_PBKDF2_ITERATIONS).contains(&iterations) {
+          return Err(invalid_data(format!(
+            "PBKDF2 iterations {} out of range [1, {}]",
+            iterations, MAX_PBKDF2_ITERATIONS
+          )));
+        }
+        Kdf::Passphrase { iterations, salt }
+      }
+      KDF_X25519 => {
+        let mut ephemeral = [0; KEY_LEN];
+        reader.read_exact(&mut ephemeral)?;
+        Kdf::X25519 { ephemeral }
+      }
+      kdf => return Err(invalid_data(format!("unknown KDF {}", kdf))),
+    };
+
+    Ok(Self {
+      file_size,
+      block_size,
+      kdf,
+    })
+  }
+
+  pub fn block_count(&self) -> u64 {
+    self.file_size.div_ceil(self.block_size)
+  }
+}
+
+fn random<const N: usize>() -> [u8; N] {
+  let mut bytes = [0; N];
+  SystemRandom::new().fill(&mut bytes).unwrap();
+  bytes
+}
+
+fn passphrase_key(passphrase: &str, iterations: u32, salt: &[u8]) -> io::Result<[u8; KEY_LEN]> {
+  let iterations = NonZeroU32::new(iterations).ok_or(invalid_data("zero PBKDF2 iterations"))?;
+  let mut key = [0; KEY_LEN];
+  pbkdf2::derive(
+    pbkdf2::PBKDF2_HMAC_SHA256,
+    iterations,
+    salt,
+    passphrase.as_bytes(),
+    &mut key,
+  );
+  Ok(key)
+}
+
+fn x25519_key(
+  secret: &StaticSecret,
+  their_public: &PublicKey,
+  ephemeral: &[u8; KEY_LEN],
+  recipient: &[u8; KEY_LEN],
+) -> io::Result<[u8; KEY_LEN]> {
+  let shared = secret.diffie_hellman(their_public);
+  if !shared.was_contributory() {
+    return Err(invalid_data("invalid X25519 key"));
+  }
+
+  let salt = [ephemeral.as_slice(), recipient.as_slice()].concat();
+  let mut key = [0; KEY_LEN];
+  hkdf::Salt::new(hkdf::HKDF_SHA256, &salt)
+    .extract(shared.as_bytes())
+    .expand(&[HKDF_INFO], hkdf::HKDF_SHA256)
+    .and_then(|okm| okm.fill(&mut key))
+    .map_err(|_| invalid_data("failed to derive key"))?;
+  Ok(key)
+}
+
+/// Generate an X25519 key pair for receiving encrypted transfers, as (secret, public).
+pub fn generate_key_pair() -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+  let secret = StaticSecret::from(random::<KEY_LEN>());
+  let public = PublicKey::from(&secret);
+  (secret.to_bytes(), public.to_bytes())
+}
+
+pub fn encode_key(key: &[u8; KEY_LEN]) -> String {
+  key.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+pub fn decode_key(hex: &str) -> Result<[u8; KEY_LEN], String> {
+  let hex = hex.trim();
+  if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+    return Err(format!("key must be {} hex digits", KEY_LEN * 2));
+  }
+  let mut key = [0; KEY_LEN];
+  for (i, x) in key.iter_mut().enumerate() {
+    *x = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|e| e.to_string())?;
+  }
+  Ok(key)
+}
+
+/// Encrypts or decrypts the blocks of one transfer with ChaCha20-Poly1305. The key is unique to
+/// the transfer, so the block ID is used as nonce.
+pub struct BlockCipher {
+  key: LessSafeKey,
+  header: Vec<u8>,
+}
+
+impl BlockCipher {
+  /// Derive a new transfer key for the recipient. The header must be stored with the file.
+  pub fn new(recipient: &Recipient, file_size: u64, block_size: u64) -> io::Result<Self> {
+    let (kdf, key) = match recipient {
+      Recipient::Passphrase(passphrase) => {
+        let salt = random::<SALT_LEN>();
+        let key = passphrase_key(passphrase, PBKDF2_ITERATIONS, &salt)?;
+        let kdf = Kdf::Passphrase {
+          iterations: PBKDF2_ITERATIONS,
+          salt,
+        };
+        (kdf, key)
+      }
+      Recipient::PublicKey(recipient) => {
+        let ephemeral_secret = StaticSecret::from(random::<KEY_LEN>());
+        let ephemeral = PublicKey::from(&ephemeral_secret).to_bytes();
+        let key = x25519_key(
+          &ephemeral_secret,
+          &PublicKey::from(*recipient),
+          &ephemeral,
+          recipient,
+        )?;
+        (Kdf::X25519 { ephemeral }, key)
+      }
+    };
+
+    let header = EncryptionHeader {
+      file_size,
+      block_size,
+      kdf,
+    };
+    Ok(Self::with_key(&header, &key))
+  }
+
+  /// Recover the transfer key from the header of an encrypted file.
+  pub fn open(header: &EncryptionHeader, secret: &Secret) -> io::Result<Self> {
+    let key = match (&header.kdf, secret) {
+      (Kdf::Passphrase { iterations, salt }, Secret::Passphrase(passphrase)) => {
+        passphrase_key(passphrase, *iterations, salt)?
+      }
+      (Kdf::X25519 { ephemeral }, Secret::SecretKey(secret_key)) => {
+        let secret = StaticSecret::from(*secret_key);
+        let recipient = PublicKey::from(&secret).to_bytes();
+        x25519_key(&secret, &PublicKey::from(*ephemeral), ephemeral, &recipient)?
+      }
+      (Kdf::Passphrase { .. }, _) => {
+        return Err(invalid_data("file is encrypted with a passphrase"))
+      }
+      (Kdf::X25519 { .. }, _) => return Err(invalid_data("file is encrypted for a recipient key")),
+    };
+    Ok(Self::with_key(header, &key))
+  }
+
+  fn with_key(header: &EncryptionHeader, key: &[u8; KEY_LEN]) -> Self {
+    Self {
+      key: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap()),
+      header: header.to_bytes(),
+    }
+  }
+
+  pub fn header(&self) -> &[u8] {
+    &self.header
+  }
+
+  fn nonce(block_id: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[8..].copy_from_slice(&block_id.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+  }
+
+  /// Encrypt a block in place, appending the tag.
+  pub fn seal_block(&self, block_id: u32, data: &mut Vec<u8>) {
+    self
+      .key
+      .seal_in_place_append_tag(Self::nonce(block_id), Aad::from(&self.header), data)
+      .unwrap();
+  }
+
+  /// Decrypt a block in place, removing the tag.
+  pub fn open_block(&self, block_id: u32, data: &mut Vec<u8>) -> io::Result<()> {
+    let len = self
+      .key
+      .open_in_place(Self::nonce(block_id), Aad::from(&self.header), data)
+      .map_err(|_| invalid_data(format!("failed to decrypt block {}", block_id)))?
+      .len();
+    data.truncate(len);
+    Ok(())
+  }
+}
+
+/// Decrypt a received file into `output`.
+pub fn decrypt_file(input: &Path, output: &Path, secret: &Secret) -> io::Result<()> {
+  let mut reader = BufReader::new(File::open(input)?);
+  let header = EncryptionHeader::read_from(&mut reader)?;
+  let cipher = BlockCipher::open(&header, secret)?;
+
+  let mut writer = BufWriter::new(File::create(output)?);
+  for block_id in 0..header.block_count() {
+    let block_len = (header.file_size - block_id * header.block_size).min(header.block_size);
+    let mut data = vec![0; (block_len + TAG_LEN) as usize];
+    reader.read_exact(&mut data)?;
+    cipher.open_block(block_id as u32, &mut data)?;
+    writer.write_all(&data)?;
+  }
+  if reader.read(&mut [0])? != 0 {
+    return Err(invalid_data("trailing data after the last block"));
+  }
+  writer.flush()
+}
@@ -0,0 +1,106 @@
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+
+use ring::digest::{digest, SHA256};
+use tokio::{
+  fs::{self, File},
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  task,
+};
+
+use crate::erasure::read_block;
+
+pub const HASH_LEN: usize = 32;
+
+pub type BlockHash = [u8; HASH_LEN];
+
+pub fn hash_block(data: &[u8]) -> BlockHash {
+  digest(&SHA256, data).as_ref().try_into().unwrap()
+}
+
+/// Hashes of every block of a file.
+pub async fn block_hashes(path: &Path, block_size: u64) -> io::Result<Vec<BlockHash>> {
+  let file = File::open(path).await?.into_std().await;
+
+  task::spawn_blocking(move || {
+    let block_count = file.metadata()?.len().div_ceil(block_size) as u32;
+    (0..block_count)
+      .map(|block_id| Ok(hash_block(&read_block(&file, block_id, block_size)?)))
+      .collect()
+  })
+  .await?
+}
+
+/// A block of an upload which is rebuilt from the previous version of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReusedBlock {
+  pub block_id: u32,
+  /// Where the block is found in the previous version.
+  pub base_block_id: u32,
+  pub hash: BlockHash,
+}
+
+impl ReusedBlock {
+  pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+    writer.write_u32(self.block_id).await?;
+    writer.write_u32(self.base_block_id).await?;
+    writer.write_all(&self.hash).await
+  }
+
+  pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+    let block_id = reader.read_u32().await?;
+    let base_block_id = reader.read_u32().await?;
+    let mut hash = [0; HASH_LEN];
+    reader.read_exact(&mut hash).await?;
+    Ok(Self {
+      block_id,
+      base_block_id,
+      hash,
+    })
+  }
+}
+
+/// Blocks of the new version found anywhere in the previous one, so moved data is reused too.
+pub fn plan_reuse(hashes: &[BlockHash], base_hashes: &[BlockHash]) -> Vec<ReusedBlock> {
+  let mut base_blocks = HashMap::new();
+  for (base_block_id, hash) in base_hashes.iter().enumerate() {
+    base_blocks.entry(hash).or_insert(base_block_id as u32);
+  }
+
+  hashes
+    .iter()
+    .enumerate()
+    .filter_map(|(block_id, hash)| {
+      base_blocks.get(hash).map(|base_block_id| ReusedBlock {
+        block_id: block_id as u32,
+        base_block_id: *base_block_id,
+        hash: *hash,
+      })
+    })
+    .collect()
+}
+
+/// Open the previous version once, for every block reused from it.
+pub async fn open_base(base_path: &Path) -> io::Result<Arc<std::fs::File>> {
+  Ok(Arc::new(File::open(base_path).await?.into_std().await))
+}
+
+/// Copy a block of `block_len` bytes from the previous version to `tmp_path/<block_id>`, where
+/// decoded blocks are written. Returns false if the block changed since its hash was taken.
+pub async fn reuse_block(
+  base: &Arc<std::fs::File>,
+  block_size: u64,
+  block: &ReusedBlock,
+  block_len: u64,
+  tmp_path: &Path,
+) -> io::Result<bool> {
+  let base = base.clone();
+  let base_block_id = block.base_block_id;
+  let data = task::spawn_blocking(move || read_block(&base, base_block_id, block_size)).await??;
+  if data.len() as u64 != block_len || hash_block(&data) != block.hash {
+    return Ok(false);
+  }
+
+  fs::create_dir_all(tmp_path).await?;
+  fs::write(tmp_path.join(block.block_id.to_string()), data).await?;
+  Ok(true)
+}
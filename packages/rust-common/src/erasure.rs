@@ -1,71 +1,115 @@
-use std::{error::Error, io::SeekFrom, iter, path::PathBuf};
+use std::{error::Error, io, path::PathBuf, sync::Arc};
 
-use once_cell::sync::Lazy;
-use raptorq::{
-  EncodingPacket, ObjectTransmissionInformation, SourceBlockDecoder, SourceBlockEncoder,
-  SourceBlockEncodingPlan,
-};
 use tokio::{
   fs::{self, File},
-  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+  io::AsyncWriteExt,
   task,
 };
 
-pub const BLOCK_SIZE: u64 = 1 * 1024 * 1024;
-pub const MAX_PACKET_SIZE: u16 = 1024;
-
-pub const TRANSFER_LENGTH: u64 = BLOCK_SIZE;
-pub const SYMBOL_SIZE: u16 = MAX_PACKET_SIZE - MAX_PACKET_SIZE % ALIGNMENT as u16;
-pub const SOURCE_BLOCKS: u8 = 1;
-pub const SUB_BLOCKS: u16 = 1;
-pub const ALIGNMENT: u8 = 8;
-
-pub const DATA_PACKET_COUNT_PER_BLOCK: usize = BLOCK_SIZE as usize / MAX_PACKET_SIZE as usize;
-
-static ENCODE_CONFIG: Lazy<ObjectTransmissionInformation> = Lazy::new(|| {
-  ObjectTransmissionInformation::new(
-    TRANSFER_LENGTH,
-    SYMBOL_SIZE,
-    SOURCE_BLOCKS,
-    SUB_BLOCKS,
-    ALIGNMENT,
-  )
-});
-static ENCODE_PLAN: Lazy<SourceBlockEncodingPlan> =
-  Lazy::new(|| SourceBlockEncodingPlan::generate((BLOCK_SIZE / SYMBOL_SIZE as u64) as u16));
+use crate::{crypto::BlockCipher, params::TransferParams};
 
-pub async fn encode_block(
-  file: &File,
-  block_id: u32,
-  parity_rate: f32,
-) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
-  let mut file = file.try_clone().await?;
+#[cfg(unix)]
+fn read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+  std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
 
-  file
-    .seek(SeekFrom::Start(block_id as u64 * BLOCK_SIZE))
-    .await?;
+#[cfg(windows)]
+fn read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+  std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
 
-  let mut block_data = bytes::BytesMut::zeroed(BLOCK_SIZE as usize);
-  let mut reader = file.take(BLOCK_SIZE);
+/// Read the real bytes of a block. Reads by offset, since cloned handles share one cursor and
+/// blocks may be read concurrently.
+pub(crate) fn read_block(
+  file: &std::fs::File,
+  block_id: u32,
+  block_size: u64,
+) -> io::Result<Vec<u8>> {
+  let offset = block_id as u64 * block_size;
+  let mut block_data = vec![0; block_size as usize];
 
   let mut read_bytes: usize = 0;
-  while read_bytes < BLOCK_SIZE as usize {
-    match reader.read(&mut block_data[read_bytes..]).await? {
-      0 => break,
-      n => read_bytes += n,
+  while read_bytes < block_data.len() {
+    match read_at(
+      file,
+      &mut block_data[read_bytes..],
+      offset + read_bytes as u64,
+    ) {
+      Ok(0) => break,
+      Ok(n) => read_bytes += n,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
     }
   }
 
-  let parity_per_block = (parity_rate * (BLOCK_SIZE as f32 / MAX_PACKET_SIZE as f32)) as u32;
+  block_data.truncate(read_bytes);
+  Ok(block_data)
+}
 
-  let packets = task::spawn_blocking(move || {
-    let block_encoder =
-      SourceBlockEncoder::with_encoding_plan(0, &ENCODE_CONFIG, &block_data, &ENCODE_PLAN);
-    let mut packets = block_encoder.source_packets();
-    packets.extend(block_encoder.repair_packets(0, parity_per_block));
-    let packets = packets.into_iter().map(|x| x.serialize()).collect();
+/// Packets of an encoded block.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockPackets {
+  /// Length of the data the packets encode, which differs from the block length if the block is
+  /// compressed or encrypted. The server needs it to decode the block.
+  pub encoded_len: u32,
+  pub packets: Vec<Vec<u8>>,
+}
+
+/// Encode a block into packets with the codec of the transfer. The last block is encoded with
+/// its real length, so small files only produce a handful of packets.
+///
+/// The block is compressed first if the transfer uses compression and the block shrinks. With a
+/// `cipher`, it's encrypted before encoding and `params.encrypted` must be set.
+pub async fn encode_block(
+  file: &File,
+  block_id: u32,
+  parity_rate: f32,
+  params: &TransferParams,
+  cipher: Option<Arc<BlockCipher>>,
+) -> Result<BlockPackets, Box<dyn Error>> {
+  let file = file.try_clone().await?.into_std().await;
+  let block_size = params.block_size;
+
+  let block_data = task::spawn_blocking(move || read_block(&file, block_id, block_size)).await??;
+  if block_data.is_empty() {
+    return Err(format!("block {} is out of range", block_id).into());
+  }
+  encode_block_data(block_id, block_data, parity_rate, params, cipher).await
+}
+
+/// Same as [`encode_block`], for a block which was already read, e.g. from a stream.
+pub async fn encode_block_data(
+  block_id: u32,
+  mut block_data: Vec<u8>,
+  parity_rate: f32,
+  params: &TransferParams,
+  cipher: Option<Arc<BlockCipher>>,
+) -> Result<BlockPackets, Box<dyn Error>> {
+  if cipher.is_some() != params.encrypted {
+    return Err("cipher does not match the encrypted flag".into());
+  }
+  let params = *params;
 
-    packets
+  if let Some(cipher) = cipher {
+    block_data = task::spawn_blocking(move || {
+      cipher.seal_block(block_id, &mut block_data);
+      block_data
+    })
+    .await?;
+  }
+
+  let packets = task::spawn_blocking(move || {
+    let block_data = params
+      .compression
+      .compress(&block_data)
+      .unwrap_or(block_data);
+    BlockPackets {
+      encoded_len: block_data.len() as u32,
+      packets: params
+        .codec
+        .codec()
+        .encode(&params, &block_data, parity_rate),
+    }
   })
   .await
   .unwrap();
@@ -73,42 +117,50 @@ pub async fn encode_block(
   Ok(packets)
 }
 
+/// Decode a block of `encoded_len` bytes and write it to `tmp_base_path/<uuid>/<block_id>`,
+/// decompressed if the block was compressed.
 pub async fn decode_block(
   uuid: u128,
   block_id: u32,
   file_size: u64,
-  mut packets: Vec<bytes::Bytes>,
+  encoded_len: u64,
+  params: &TransferParams,
+  packets: Vec<bytes::Bytes>,
   tmp_base_path: &str,
 ) -> Result<(), Box<dyn Error>> {
-  let data = task::spawn_blocking(move || -> Result<Vec<u8>, String> {
-    let mut block_decoder = SourceBlockDecoder::new(0, &ENCODE_CONFIG, BLOCK_SIZE);
-
-    let mut result;
-    while !packets.is_empty() {
-      result = block_decoder.decode(iter::once(EncodingPacket::deserialize(
-        &packets.pop().unwrap(),
-      )));
-      if let Some(decoded) = result {
-        return Ok(decoded);
-      }
+  if !params.is_valid_encoded_len(file_size, block_id, encoded_len) {
+    return Err(
+      format!(
+        "invalid encoded length {} of block {}",
+        encoded_len, block_id
+      )
+      .into(),
+    );
+  }
+  // A block is only compressed if that makes it shorter
+  let block_len = params.encoded_block_len(file_size, block_id);
+  let compressed = encoded_len < block_len;
+  let params = *params;
+
+  let data = task::spawn_blocking(move || -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let data = params
+      .codec
+      .codec()
+      .decode(&params, encoded_len, packets)
+      .ok_or("failed to decode file")?;
+    if compressed {
+      return Ok(params.compression.decompress(&data, block_len as usize)?);
     }
-    Err("failed to decode file".into())
+    Ok(data)
   })
-  .await??;
-
-  let cursor_start = BLOCK_SIZE * block_id as u64;
-  let data_end = if cursor_start + BLOCK_SIZE < file_size {
-    BLOCK_SIZE as usize
-  } else {
-    (file_size - cursor_start) as usize
-  };
+  .await?
+  .map_err(|e| e.to_string())?;
 
   let base_path = PathBuf::from(tmp_base_path).join(uuid.to_string());
   fs::create_dir_all(&base_path).await?;
   let mut file = File::create(base_path.join(block_id.to_string())).await?;
 
-  // file.seek(SeekFrom::Start(cursor_start)).await?;
-  file.write_all(&data[0..data_end]).await?;
+  file.write_all(&data).await?;
 
   Ok(())
 }
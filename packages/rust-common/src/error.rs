@@ -0,0 +1,79 @@
+use std::{error::Error, fmt};
+
+/// Reason of a rejected upload request, sent as the u8 code after `FLAG_ERROR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+  InvalidParams,
+  /// Missing or unknown access token.
+  Unauthorized,
+  /// No previous version to upload a delta against.
+  NotFound,
+  /// No routing rule allows the upload.
+  Forbidden,
+  /// The server doesn't have enough disk space for the file.
+  InsufficientSpace,
+  /// The file exceeds the upload quota of the client.
+  QuotaExceeded,
+  /// The file exceeds the max file size of the server.
+  FileTooLarge,
+  FilenameTooLong,
+  /// The connection or the server has as many uploads in flight as it allows.
+  TooManyTasks,
+  /// The file was received, but merging it or handing it to the sink failed.
+  StoreFailed,
+  Unknown(u8),
+}
+
+impl RequestError {
+  pub fn code(&self) -> u8 {
+    match self {
+      Self::InvalidParams => 1,
+      Self::Unauthorized => 2,
+      Self::NotFound => 3,
+      Self::Forbidden => 4,
+      Self::InsufficientSpace => 5,
+      Self::QuotaExceeded => 6,
+      Self::FileTooLarge => 7,
+      Self::FilenameTooLong => 8,
+      Self::TooManyTasks => 9,
+      Self::StoreFailed => 10,
+      Self::Unknown(code) => *code,
+    }
+  }
+
+  pub fn from_code(code: u8) -> Self {
+    match code {
+      1 => Self::InvalidParams,
+      2 => Self::Unauthorized,
+      3 => Self::NotFound,
+      4 => Self::Forbidden,
+      5 => Self::InsufficientSpace,
+      6 => Self::QuotaExceeded,
+      7 => Self::FileTooLarge,
+      8 => Self::FilenameTooLong,
+      9 => Self::TooManyTasks,
+      10 => Self::StoreFailed,
+      code => Self::Unknown(code),
+    }
+  }
+}
+
+impl fmt::Display for RequestError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidParams => write!(f, "unsupported transfer parameters"),
+      Self::Unauthorized => write!(f, "invalid access token"),
+      Self::NotFound => write!(f, "no previous version of the file"),
+      Self::Forbidden => write!(f, "no routing rule allows the upload"),
+      Self::InsufficientSpace => write!(f, "not enough disk space on the server"),
+      Self::QuotaExceeded => write!(f, "upload quota exceeded"),
+      Self::FileTooLarge => write!(f, "file exceeds the max file size of the server"),
+      Self::FilenameTooLong => write!(f, "filename is too long"),
+      Self::TooManyTasks => write!(f, "too many uploads in progress"),
+      Self::StoreFailed => write!(f, "server failed to store the file"),
+      Self::Unknown(code) => write!(f, "unknown error ({})", code),
+    }
+  }
+}
+
+impl Error for RequestError {}
@@ -3,21 +3,64 @@ pub const FLAG_LENGTH: usize = 8;
 pub const FLAG_OK: u8 = 0b00000000;
 pub const FLAG_ERROR: u8 = 0b00000001;
 
-/// Request a unique ID for uploading. Next is the u64 file size and UTF-8 filename.
-/// Response with u128 ID.
+/// Request a unique ID for uploading. Next is the u64 file size, transfer params (u64 block size,
+/// u16 symbol size, u8 codec ID, u8 source blocks, u8 flags, u8 compression ID, u8 interleave
+/// depth), u16 length prefixed access token (empty if none), u16 length prefixed encryption header (empty unless the
+/// encrypted flag is set), u16 length prefixed UTF-8 destination tag (empty if none) and UTF-8
+/// filename.
+/// Response with OK and u128 ID, or ERROR and u8 error code.
+/// An empty file is complete once OK is received, no packets or `FLAG_UPLOAD_COMPLETE` follow.
+/// A streamed upload sends `UNKNOWN_FILE_SIZE` and announces the size with `FLAG_FILE_SIZE`.
 pub const FLAG_REQUEST_ID: u8 = 0b00000010;
 
-/// Upload a file packet. Next is the u128 ID, u32 packet ID and packet content.
+/// File size of an upload streamed from a source of unknown length. Every block is full until
+/// the size is announced.
+pub const UNKNOWN_FILE_SIZE: u64 = u64::MAX;
+
+/// Announce the size of a streamed upload once the stream ended, before any packet of its last
+/// block. Next is the u128 ID and u64 file size.
+/// Response with OK, or ERROR and u8 error code.
+/// Every other bit is taken by a request. ERROR only appears in responses, so its bit is free as a
+/// request flag.
+pub const FLAG_FILE_SIZE: u8 = 0b00000001;
+
+/// Upload a file packet. Next is the u128 ID, u32 packet ID, u32 encoded length of the block and
+/// packet content.
 /// Response with OK.
 pub const FLAG_UPLOAD_PACKET: u8 = 0b00000100;
 
+/// Length of the `FLAG_UPLOAD_PACKET` datagram header: flag, u128 ID, u32 packet ID and u32
+/// encoded length.
+pub const UPLOAD_PACKET_HEADER_LENGTH: usize = 1 + 16 + 4 + 4;
+
 /// Inform server upload complete. Next is the u128 ID.
 pub const FLAG_UPLOAD_COMPLETE: u8 = 0b00001000;
 
+/// Change the symbol size of an upload, e.g. after the path MTU changed. Next is the u128 ID and
+/// u16 symbol size. Packets buffered with the previous symbol size are discarded.
+/// Response with OK, or ERROR and u8 error code.
+pub const FLAG_UPDATE_PARAMS: u8 = 0b00010000;
+
+/// Request per-block SHA-256 hashes of the previously received version of a file, to upload only
+/// the blocks which changed. Next is the u64 block size, u16 length prefixed access token, u16
+/// length prefixed UTF-8 destination tag and UTF-8 filename.
+/// Response with OK, u64 file size, u32 block count and 32 byte hash of each block, or ERROR and
+/// u8 error code.
+pub const FLAG_BLOCK_HASHES: u8 = 0b00100000;
+
+/// Rebuild blocks of an upload from the previous version of a file instead of sending them. Next
+/// is the u128 ID, u16 length prefixed UTF-8 filename of the previous version, u32 count and for
+/// each block the u32 packet ID, u32 packet ID in the previous version and 32 byte hash.
+/// Blocks whose hash no longer matches are left missing, so they are sent when the upload
+/// completes.
+/// Response with OK and u32 count of reused blocks, or ERROR and u8 error code.
+pub const FLAG_REUSE_BLOCKS: u8 = 0b01000000;
+
 /// Server decoded the file successfully.
 pub const FLAG_FILE_DECODE_OK: u8 = FLAG_OK;
 
 /// Server decoded the file failed. Next is the u32 missing packet length, and N u32 packet ID.
+/// No missing packets means every block was received, but the server failed to store the file.
 pub const FLAG_FILE_DECODE_ERROR: u8 = FLAG_ERROR;
 
 /// Heartbeat.
@@ -0,0 +1,29 @@
+use std::{io::Read, path::Path};
+
+use ring::digest::{Context, SHA256};
+use tokio::{fs::File, io, task};
+
+/// SHA-256 of a whole file, as lowercase hex.
+pub async fn file_hash(path: &Path) -> io::Result<String> {
+  let mut file = File::open(path).await?.into_std().await;
+
+  task::spawn_blocking(move || {
+    let mut context = Context::new(&SHA256);
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+      match file.read(&mut buf)? {
+        0 => break,
+        n => context.update(&buf[..n]),
+      }
+    }
+    Ok(
+      context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect(),
+    )
+  })
+  .await?
+}
@@ -1,2 +1,12 @@
+pub mod codec;
+pub mod compress;
+pub mod crypto;
+pub mod delta;
 pub mod erasure;
+pub mod error;
 pub mod flags;
+pub mod hash;
+pub mod params;
+pub mod pipeline;
+pub mod schedule;
+pub mod tls;
@@ -0,0 +1,210 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+  codec::{CodecKind, ALIGNMENT, PAYLOAD_ID_LENGTH},
+  compress::Compression,
+  crypto::TAG_LEN,
+  flags::UPLOAD_PACKET_HEADER_LENGTH,
+};
+
+pub const MIN_BLOCK_SIZE: u64 = 256 * 1024;
+pub const MAX_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+/// Blocks split into several RaptorQ source blocks may be larger.
+pub const MAX_PARTITIONED_BLOCK_SIZE: u64 = 256 * 1024 * 1024;
+
+pub const MIN_SYMBOL_SIZE: u16 = 64;
+pub const DEFAULT_SYMBOL_SIZE: u16 = 1024;
+
+pub const DEFAULT_INTERLEAVE_DEPTH: u8 = 4;
+
+const FLAG_ENCRYPTED: u8 = 1;
+
+/// Per-transfer coding parameters, negotiated in the upload request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferParams {
+  pub block_size: u64,
+  pub symbol_size: u16,
+  pub codec: CodecKind,
+  /// RaptorQ source blocks per block. 1 encodes each block as a single source block, 0 derives
+  /// the partitioning from the real block length.
+  pub source_blocks: u8,
+  /// Blocks are sealed with a [`BlockCipher`](crate::crypto::BlockCipher) before encoding, so
+  /// each carries an authentication tag.
+  pub encrypted: bool,
+  /// Blocks which shrink are compressed before encoding, so their encoded length varies and is
+  /// sent with every packet.
+  pub compression: Compression,
+  /// Blocks whose packets are interleaved, so the server buffers as many partial blocks at once.
+  pub interleave_depth: u8,
+}
+
+impl Default for TransferParams {
+  fn default() -> Self {
+    Self {
+      block_size: DEFAULT_BLOCK_SIZE,
+      symbol_size: DEFAULT_SYMBOL_SIZE,
+      codec: CodecKind::default(),
+      source_blocks: 1,
+      encrypted: false,
+      compression: Compression::None,
+      interleave_depth: DEFAULT_INTERLEAVE_DEPTH,
+    }
+  }
+}
+
+impl TransferParams {
+  pub fn validate(&self) -> Result<(), String> {
+    let max_block_size = if self.source_blocks == 1 {
+      MAX_BLOCK_SIZE
+    } else {
+      MAX_PARTITIONED_BLOCK_SIZE
+    };
+    if !(MIN_BLOCK_SIZE..=max_block_size).contains(&self.block_size) {
+      return Err(format!(
+        "block size {} out of range [{}, {}]",
+        self.block_size, MIN_BLOCK_SIZE, max_block_size
+      ));
+    }
+    if self.symbol_size < MIN_SYMBOL_SIZE || self.symbol_size % ALIGNMENT as u16 != 0 {
+      return Err(format!(
+        "symbol size {} must be at least {} and aligned to {}",
+        self.symbol_size, MIN_SYMBOL_SIZE, ALIGNMENT
+      ));
+    }
+    if self.interleave_depth == 0 {
+      return Err("interleave depth must be positive".into());
+    }
+    // The server decompresses blocks, which it can't do without the key
+    if self.encrypted && self.compression != Compression::None {
+      return Err("compression can't be combined with end-to-end encryption".into());
+    }
+    self.codec.codec().validate(self)
+  }
+
+  /// Largest aligned symbol size whose upload packet fits in a datagram of `max_datagram_size`.
+  pub fn symbol_size_for_datagram(max_datagram_size: usize) -> Option<u16> {
+    let available = max_datagram_size
+      .checked_sub(UPLOAD_PACKET_HEADER_LENGTH + PAYLOAD_ID_LENGTH)?
+      .min(u16::MAX as usize) as u16;
+    let symbol_size = available - available % ALIGNMENT as u16;
+
+    (symbol_size >= MIN_SYMBOL_SIZE).then_some(symbol_size)
+  }
+
+  /// Saturates at `u32::MAX`, e.g. for an unknown file size.
+  pub fn block_count(&self, file_size: u64) -> u32 {
+    file_size.div_ceil(self.block_size).min(u32::MAX as u64) as u32
+  }
+
+  /// Bytes added to every block before encoding.
+  pub fn block_overhead(&self) -> u64 {
+    if self.encrypted {
+      TAG_LEN
+    } else {
+      0
+    }
+  }
+
+  /// Encoded length of a full block.
+  pub fn encoded_block_size(&self) -> u64 {
+    self.block_size + self.block_overhead()
+  }
+
+  /// Source symbols needed to rebuild a full block.
+  pub fn source_symbols(&self) -> u32 {
+    self.encoded_block_size().div_ceil(self.symbol_size as u64) as u32
+  }
+
+  /// Bytes of packets buffered to rebuild a window of interleaved full blocks, each packet
+  /// holding a symbol and its packet ID.
+  pub fn window_buffer_size(&self) -> u64 {
+    self.source_symbols() as u64
+      * (self.symbol_size as u64 + PAYLOAD_ID_LENGTH as u64)
+      * self.interleave_depth as u64
+  }
+
+  /// Real length of a block. Only the last block may be shorter than `block_size`.
+  pub fn block_len(&self, file_size: u64, block_id: u32) -> u64 {
+    let start = block_id as u64 * self.block_size;
+    file_size.saturating_sub(start).min(self.block_size)
+  }
+
+  /// Length of the block as encoded, including the overhead. Compressed blocks are shorter.
+  pub fn encoded_block_len(&self, file_size: u64, block_id: u32) -> u64 {
+    match self.block_len(file_size, block_id) {
+      0 => 0,
+      len => len + self.block_overhead(),
+    }
+  }
+
+  /// Whether a block may be encoded to `encoded_len` bytes. With compression, any length up to
+  /// the block length is possible, and a block of the full length isn't compressed.
+  pub fn is_valid_encoded_len(&self, file_size: u64, block_id: u32, encoded_len: u64) -> bool {
+    let max_len = self.encoded_block_len(file_size, block_id);
+    match self.compression {
+      Compression::None => encoded_len == max_len,
+      _ => encoded_len > 0 && encoded_len <= max_len,
+    }
+  }
+
+  /// Source symbols needed to rebuild `encoded_len` bytes.
+  pub fn encoded_source_symbols(&self, encoded_len: u64) -> u32 {
+    encoded_len.div_ceil(self.symbol_size as u64) as u32
+  }
+
+  /// Source symbols needed to rebuild the block, taking a partial last block into account.
+  pub fn block_source_symbols(&self, file_size: u64, block_id: u32) -> u32 {
+    self.encoded_source_symbols(self.encoded_block_len(file_size, block_id))
+  }
+
+  /// Write as u64 block size, u16 symbol size, u8 codec ID, u8 source blocks, u8 flags, u8
+  /// compression ID and u8 interleave depth.
+  pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+    writer.write_u64(self.block_size).await?;
+    writer.write_u16(self.symbol_size).await?;
+    writer.write_u8(self.codec.id()).await?;
+    writer.write_u8(self.source_blocks).await?;
+    writer
+      .write_u8(if self.encrypted { FLAG_ENCRYPTED } else { 0 })
+      .await?;
+    writer.write_u8(self.compression.id()).await?;
+    writer.write_u8(self.interleave_depth).await?;
+    Ok(())
+  }
+
+  /// Fails with `InvalidData` if the codec, a flag or the compression is unknown.
+  pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+    let block_size = reader.read_u64().await?;
+    let symbol_size = reader.read_u16().await?;
+    let codec_id = reader.read_u8().await?;
+    let source_blocks = reader.read_u8().await?;
+    let flags = reader.read_u8().await?;
+    let compression_id = reader.read_u8().await?;
+    let interleave_depth = reader.read_u8().await?;
+    // Checked after reading all fields, so the reader is left at what follows the params
+    let codec = CodecKind::from_id(codec_id).ok_or(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("unknown codec ID {}", codec_id),
+    ))?;
+    if flags & !FLAG_ENCRYPTED != 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unknown flags {:#04x}", flags),
+      ));
+    }
+    let compression = Compression::from_id(compression_id).ok_or(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("unknown compression ID {}", compression_id),
+    ))?;
+    Ok(Self {
+      block_size,
+      symbol_size,
+      codec,
+      source_blocks,
+      encrypted: flags & FLAG_ENCRYPTED != 0,
+      compression,
+      interleave_depth,
+    })
+  }
+}
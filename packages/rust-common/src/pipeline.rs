@@ -0,0 +1,200 @@
+use std::{collections::VecDeque, future::Future, sync::Arc};
+
+use tokio::{
+  fs::File,
+  io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+  sync::{mpsc, Semaphore},
+  task::JoinHandle,
+};
+
+use crate::{
+  crypto::BlockCipher,
+  erasure::{encode_block, encode_block_data, BlockPackets},
+  params::TransferParams,
+};
+
+/// An encoded block, or the reason it couldn't be encoded.
+pub type EncodedBlock = Result<(u32, BlockPackets), String>;
+
+/// Read and encode blocks ahead of the sender on up to `workers` blocks at once, yielding them
+/// in order of `block_ids`. Up to `queued` encoded blocks wait for the receiver, so the pipeline
+/// holds at most `workers + queued` blocks, besides those the receiver keeps. Dropping the
+/// receiver stops the pipeline.
+pub fn encode_pipeline(
+  file: File,
+  block_ids: Vec<u32>,
+  parity_rate: f32,
+  params: TransferParams,
+  cipher: Option<Arc<BlockCipher>>,
+  workers: usize,
+  queued: usize,
+) -> mpsc::Receiver<EncodedBlock> {
+  let (tx, rx) = mpsc::channel(queued.max(1));
+
+  tokio::spawn(async move {
+    let mut block_ids = block_ids.into_iter();
+    let mut in_flight: VecDeque<JoinHandle<EncodedBlock>> = VecDeque::new();
+
+    loop {
+      while in_flight.len() < workers.max(1) {
+        let Some(block_id) = block_ids.next() else {
+          break;
+        };
+        let file = match file.try_clone().await {
+          Ok(file) => file,
+          Err(e) => {
+            let _ = tx.send(Err(e.to_string())).await;
+            return;
+          }
+        };
+        let cipher = cipher.clone();
+        in_flight.push_back(tokio::spawn(async move {
+          encode_block(&file, block_id, parity_rate, &params, cipher)
+            .await
+            .map(|packets| (block_id, packets))
+            .map_err(|e| e.to_string())
+        }));
+      }
+
+      let Some(handle) = in_flight.pop_front() else {
+        return;
+      };
+      let encoded = handle.await.unwrap_or_else(|e| Err(e.to_string()));
+      if tx.send(encoded).await.is_err() {
+        return;
+      }
+    }
+  });
+
+  rx
+}
+
+/// Output of [`stream_pipeline`].
+#[derive(Debug)]
+pub enum StreamEvent {
+  Block(u32, BlockPackets),
+  /// The stream ended after this many bytes. Yielded before the last block, so the size can be
+  /// announced before the last block is sent.
+  End(u64),
+}
+
+/// Read a stream of unknown length block by block and encode the blocks as they are read, like
+/// [`encode_pipeline`]. Every block read is appended to `spool`, so lost blocks can be encoded
+/// again from it once the stream ended. The spool is flushed before `End` is yielded. One block
+/// more than the workers is read to find the end, so at most `workers + 1 + queued` blocks are
+/// held.
+pub fn stream_pipeline<R: AsyncRead + Unpin + Send + 'static>(
+  mut reader: R,
+  mut spool: File,
+  parity_rate: f32,
+  params: TransferParams,
+  workers: usize,
+  queued: usize,
+) -> mpsc::Receiver<Result<StreamEvent, String>> {
+  let (tx, rx) = mpsc::channel(queued.max(1));
+
+  tokio::spawn(async move {
+    let mut in_flight: VecDeque<JoinHandle<EncodedBlock>> = VecDeque::new();
+    let mut next_block_id = 0u32;
+    let mut size = 0u64;
+    let mut ended = false;
+    let mut end_sent = false;
+
+    loop {
+      // One block more than the workers is read, so the end is known before the last block
+      while !ended && in_flight.len() <= workers.max(1) {
+        let block = match read_stream_block(&mut reader, params.block_size).await {
+          Ok(block) => block,
+          Err(e) => {
+            let _ = tx.send(Err(e.to_string())).await;
+            return;
+          }
+        };
+        ended = (block.len() as u64) < params.block_size;
+        if block.is_empty() {
+          break;
+        }
+        if next_block_id == u32::MAX {
+          let _ = tx.send(Err("stream has too many blocks".into())).await;
+          return;
+        }
+        if let Err(e) = spool.write_all(&block).await {
+          let _ = tx.send(Err(e.to_string())).await;
+          return;
+        }
+        size += block.len() as u64;
+
+        let block_id = next_block_id;
+        next_block_id += 1;
+        in_flight.push_back(tokio::spawn(async move {
+          encode_block_data(block_id, block, parity_rate, &params, None)
+            .await
+            .map(|packets| (block_id, packets))
+            .map_err(|e| e.to_string())
+        }));
+      }
+
+      if ended && !end_sent && in_flight.len() <= 1 {
+        if let Err(e) = spool.flush().await {
+          let _ = tx.send(Err(e.to_string())).await;
+          return;
+        }
+        end_sent = true;
+        if tx.send(Ok(StreamEvent::End(size))).await.is_err() {
+          return;
+        }
+      }
+
+      let Some(handle) = in_flight.pop_front() else {
+        return;
+      };
+      let encoded = handle.await.unwrap_or_else(|e| Err(e.to_string()));
+      let event = encoded.map(|(block_id, packets)| StreamEvent::Block(block_id, packets));
+      if tx.send(event).await.is_err() {
+        return;
+      }
+    }
+  });
+
+  rx
+}
+
+/// Read up to `block_size` bytes. Only the last block of the stream is shorter.
+async fn read_stream_block<R: AsyncRead + Unpin>(
+  reader: &mut R,
+  block_size: u64,
+) -> io::Result<Vec<u8>> {
+  let mut block = Vec::with_capacity(block_size as usize);
+  reader.take(block_size).read_to_end(&mut block).await?;
+  Ok(block)
+}
+
+/// Bounds the blocks decoded at once, so concurrent uploads share the CPU instead of piling up
+/// on the blocking thread pool.
+pub struct DecodePool {
+  permits: Semaphore,
+}
+
+impl Default for DecodePool {
+  fn default() -> Self {
+    Self::new(
+      std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1),
+    )
+  }
+}
+
+impl DecodePool {
+  pub fn new(workers: usize) -> Self {
+    Self {
+      permits: Semaphore::new(workers.max(1)),
+    }
+  }
+
+  /// Run a decode, e.g. [`decode_block`](crate::erasure::decode_block), once a worker is free.
+  pub async fn run<F: Future>(&self, decode: F) -> F::Output {
+    let _permit = self.permits.acquire().await.unwrap();
+    decode.await
+  }
+}
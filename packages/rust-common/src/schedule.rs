@@ -0,0 +1,43 @@
+use std::{collections::VecDeque, vec};
+
+/// Round-robin packets over a window of blocks, so a loss burst is spread thinly over `depth`
+/// blocks instead of wiping out one of them.
+pub struct Interleaver {
+  depth: usize,
+  window: VecDeque<(u32, vec::IntoIter<Vec<u8>>)>,
+}
+
+impl Interleaver {
+  pub fn new(depth: usize) -> Self {
+    Self {
+      depth: depth.max(1),
+      window: VecDeque::new(),
+    }
+  }
+
+  /// Whether the window has room for another block.
+  pub fn is_full(&self) -> bool {
+    self.window.len() >= self.depth
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.window.is_empty()
+  }
+
+  pub fn push(&mut self, block_id: u32, packets: Vec<Vec<u8>>) {
+    self.window.push_back((block_id, packets.into_iter()));
+  }
+
+  /// Next packet to send and its block ID. A block leaves the window after its last packet.
+  pub fn next_packet(&mut self) -> Option<(u32, Vec<u8>)> {
+    while let Some((block_id, mut packets)) = self.window.pop_front() {
+      if let Some(packet) = packets.next() {
+        if packets.len() > 0 {
+          self.window.push_back((block_id, packets));
+        }
+        return Some((block_id, packet));
+      }
+    }
+    None
+  }
+}
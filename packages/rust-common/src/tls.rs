@@ -0,0 +1,43 @@
+use std::io;
+
+use ring::digest::{digest, SHA256};
+use rustls_pemfile::Item;
+
+/// SHA-256 fingerprint of a DER encoded certificate, as colon separated lowercase hex.
+pub fn fingerprint(cert: &[u8]) -> String {
+  digest(&SHA256, cert)
+    .as_ref()
+    .iter()
+    .map(|x| format!("{:02x}", x))
+    .collect::<Vec<_>>()
+    .join(":")
+}
+
+/// Whether a fingerprint written by hand matches, ignoring case, colons and whitespace.
+pub fn fingerprint_matches(fingerprint: &str, expected: &str) -> bool {
+  let normalize = |s: &str| {
+    s.chars()
+      .filter(|x| *x != ':' && !x.is_whitespace())
+      .collect::<String>()
+      .to_lowercase()
+  };
+  normalize(fingerprint) == normalize(expected)
+}
+
+/// DER certificates of a PEM file, in order. A certificate chain starts with the end entity.
+pub fn pem_certs(mut pem: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+  rustls_pemfile::certs(&mut pem)
+}
+
+/// The first PKCS#8, PKCS#1 or SEC1 private key of a PEM file, as DER.
+pub fn pem_private_key(mut pem: &[u8]) -> io::Result<Vec<u8>> {
+  while let Some(item) = rustls_pemfile::read_one(&mut pem)? {
+    if let Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) = item {
+      return Ok(key);
+    }
+  }
+  Err(io::Error::new(
+    io::ErrorKind::InvalidData,
+    "no private key found",
+  ))
+}
@@ -0,0 +1,173 @@
+use std::{path::Path, sync::Arc};
+
+use rust_common::{
+  codec::CodecKind,
+  compress::Compression,
+  crypto::{self, BlockCipher, EncryptionHeader, Recipient, Secret},
+  erasure::{decode_block, encode_block},
+  params::TransferParams,
+};
+use tokio::fs::{self, File};
+
+const PARAMS: TransferParams = TransferParams {
+  block_size: 256 * 1024,
+  symbol_size: 1024,
+  codec: CodecKind::RaptorQ,
+  source_blocks: 1,
+  encrypted: true,
+  compression: Compression::None,
+  interleave_depth: 1,
+};
+
+/// Upload `len` bytes encrypted for `recipient` the way the server stores them: the header
+/// followed by every decoded block. Returns the path of the stored file.
+async fn upload(dir: &Path, len: usize, recipient: &Recipient) -> (Vec<u8>, std::path::PathBuf) {
+  fs::create_dir_all(dir).await.unwrap();
+  let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+  fs::write(dir.join("source"), &data).await.unwrap();
+  let file = File::open(dir.join("source")).await.unwrap();
+
+  let cipher = Arc::new(BlockCipher::new(recipient, len as u64, PARAMS.block_size).unwrap());
+  let tmp_base_path = dir.join("tmp").to_string_lossy().to_string();
+  let mut stored = cipher.header().to_vec();
+  for block_id in 0..PARAMS.block_count(len as u64) {
+    let encoded = encode_block(&file, block_id, 0.1, &PARAMS, Some(cipher.clone()))
+      .await
+      .unwrap();
+    let packets = encoded
+      .packets
+      .into_iter()
+      .skip(1)
+      .map(Into::into)
+      .collect();
+    let encoded_len = encoded.encoded_len as u64;
+    decode_block(
+      1,
+      block_id,
+      len as u64,
+      encoded_len,
+      &PARAMS,
+      packets,
+      &tmp_base_path,
+    )
+    .await
+    .unwrap();
+    let block = fs::read(dir.join("tmp").join("1").join(block_id.to_string()))
+      .await
+      .unwrap();
+    assert_eq!(
+      block.len() as u64,
+      PARAMS.encoded_block_len(len as u64, block_id)
+    );
+    stored.extend(block);
+  }
+  assert_ne!(
+    &stored[stored.len() - len.min(64)..],
+    &data[len - len.min(64)..]
+  );
+
+  let stored_path = dir.join("stored");
+  fs::write(&stored_path, stored).await.unwrap();
+  (data, stored_path)
+}
+
+#[tokio::test]
+async fn passphrase_round_trip() {
+  let dir = std::env::temp_dir().join(format!("qft-crypto-pass-{}", std::process::id()));
+  let recipient = Recipient::Passphrase("correct horse".to_string());
+  let (data, stored) = upload(&dir, PARAMS.block_size as usize * 2 + 10, &recipient).await;
+
+  let output = dir.join("output");
+  let secret = Secret::Passphrase("correct horse".to_string());
+  crypto::decrypt_file(&stored, &output, &secret).unwrap();
+  assert_eq!(fs::read(&output).await.unwrap(), data);
+
+  let wrong = Secret::Passphrase("wrong horse".to_string());
+  assert!(crypto::decrypt_file(&stored, &output, &wrong).is_err());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn recipient_key_round_trip() {
+  let dir = std::env::temp_dir().join(format!("qft-crypto-key-{}", std::process::id()));
+  let (secret_key, public_key) = crypto::generate_key_pair();
+  let public_key = crypto::decode_key(&crypto::encode_key(&public_key)).unwrap();
+  let (data, stored) = upload(&dir, 100, &Recipient::PublicKey(public_key)).await;
+
+  let output = dir.join("output");
+  crypto::decrypt_file(&stored, &output, &Secret::SecretKey(secret_key)).unwrap();
+  assert_eq!(fs::read(&output).await.unwrap(), data);
+
+  let (other_key, _) = crypto::generate_key_pair();
+  assert!(crypto::decrypt_file(&stored, &output, &Secret::SecretKey(other_key)).is_err());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn tampered_block_is_rejected() {
+  let dir = std::env::temp_dir().join(format!("qft-crypto-tamper-{}", std::process::id()));
+  let (secret_key, public_key) = crypto::generate_key_pair();
+  let (_, stored) = upload(&dir, 1000, &Recipient::PublicKey(public_key)).await;
+
+  let mut bytes = fs::read(&stored).await.unwrap();
+  let last = bytes.len() - 1;
+  bytes[last] ^= 1;
+  fs::write(&stored, bytes).await.unwrap();
+
+  let output = dir.join("output");
+  assert!(crypto::decrypt_file(&stored, &output, &Secret::SecretKey(secret_key)).is_err());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[test]
+fn header_round_trip() {
+  let cipher = BlockCipher::new(&Recipient::PublicKey([9; 32]), 12345, PARAMS.block_size).unwrap();
+  let header = EncryptionHeader::read_from(&mut cipher.header()).unwrap();
+  assert_eq!(header.file_size, 12345);
+  assert_eq!(header.block_size, PARAMS.block_size);
+  assert_eq!(header.to_bytes(), cipher.header());
+
+  assert!(EncryptionHeader::read_from(&mut &b"QFTX\x01\x02"[..]).is_err());
+}
+
+/// Header of a passphrase encrypted file with the given fields.
+fn passphrase_header(file_size: u64, block_size: u64, iterations: u32) -> Vec<u8> {
+  let mut header = b"QFTE\x01\x01".to_vec();
+  header.extend(file_size.to_be_bytes());
+  header.extend(block_size.to_be_bytes());
+  header.extend(iterations.to_be_bytes());
+  header.extend([0; 16]);
+  header
+}
+
+#[test]
+fn corrupted_header_is_rejected() {
+  let valid = passphrase_header(1000, PARAMS.block_size, crypto::PBKDF2_ITERATIONS);
+  assert!(EncryptionHeader::read_from(&mut valid.as_slice()).is_ok());
+
+  for header in [
+    passphrase_header(1000, 0, crypto::PBKDF2_ITERATIONS),
+    passphrase_header(1000, u64::MAX, crypto::PBKDF2_ITERATIONS),
+    passphrase_header(u64::MAX, PARAMS.block_size, crypto::PBKDF2_ITERATIONS),
+    passphrase_header(1000, PARAMS.block_size, 0),
+    passphrase_header(1000, PARAMS.block_size, u32::MAX),
+  ] {
+    assert!(EncryptionHeader::read_from(&mut header.as_slice()).is_err());
+  }
+}
+
+#[test]
+fn decrypting_corrupted_header_fails() {
+  let dir = std::env::temp_dir().join(format!("qft-crypto-header-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let stored = dir.join("stored");
+  std::fs::write(&stored, passphrase_header(1000, 0, 1)).unwrap();
+
+  let secret = Secret::Passphrase("correct horse".to_string());
+  assert!(crypto::decrypt_file(&stored, &dir.join("output"), &secret).is_err());
+
+  std::fs::remove_dir_all(dir).unwrap();
+}
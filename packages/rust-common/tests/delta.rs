@@ -0,0 +1,105 @@
+use rust_common::delta::{
+  self, block_hashes, hash_block, open_base, plan_reuse, reuse_block, ReusedBlock,
+};
+use tokio::fs;
+
+const BLOCK_SIZE: u64 = 1024;
+
+fn test_data(len: usize) -> Vec<u8> {
+  (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn changed_blocks_are_not_reused() {
+  let dir = std::env::temp_dir().join(format!("qft-delta-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+
+  let base = test_data(BLOCK_SIZE as usize * 4 + 10);
+  let base_path = dir.join("base");
+  fs::write(&base_path, &base).await.unwrap();
+
+  // Move blocks around, change block 1 and insert a new block
+  let block =
+    |i: usize| &base[i * BLOCK_SIZE as usize..((i + 1) * BLOCK_SIZE as usize).min(base.len())];
+  let mut changed = block(1).to_vec();
+  changed[5] ^= 1;
+  let new = [
+    block(3),
+    &changed,
+    block(0),
+    &[7; BLOCK_SIZE as usize],
+    block(2),
+    block(4),
+  ]
+  .concat();
+  let new_path = dir.join("new");
+  fs::write(&new_path, &new).await.unwrap();
+
+  let base_hashes = block_hashes(&base_path, BLOCK_SIZE).await.unwrap();
+  let hashes = block_hashes(&new_path, BLOCK_SIZE).await.unwrap();
+  assert_eq!(base_hashes.len(), 5);
+  assert_eq!(hashes.len(), 6);
+  assert_eq!(hashes[5], base_hashes[4]);
+
+  let reused = plan_reuse(&hashes, &base_hashes);
+  let pairs: Vec<(u32, u32)> = reused
+    .iter()
+    .map(|x| (x.block_id, x.base_block_id))
+    .collect();
+  assert_eq!(pairs, [(0, 3), (2, 0), (4, 2), (5, 4)]);
+
+  let tmp_path = dir.join("tmp");
+  let base = open_base(&base_path).await.unwrap();
+  for block in &reused {
+    let start = block.block_id as usize * BLOCK_SIZE as usize;
+    let block_data = &new[start..(start + BLOCK_SIZE as usize).min(new.len())];
+    let block_len = block_data.len() as u64;
+    assert!(reuse_block(&base, BLOCK_SIZE, block, block_len, &tmp_path)
+      .await
+      .unwrap());
+    let copied = fs::read(tmp_path.join(block.block_id.to_string()))
+      .await
+      .unwrap();
+    assert_eq!(copied, block_data);
+  }
+
+  // The previous version changed since the hashes were taken
+  let stale = ReusedBlock {
+    hash: hash_block(b"stale"),
+    ..reused[0]
+  };
+  assert!(
+    !reuse_block(&base, BLOCK_SIZE, &stale, BLOCK_SIZE, &tmp_path)
+      .await
+      .unwrap()
+  );
+  // A short last block can't stand in for a full block
+  let short = ReusedBlock {
+    block_id: 5,
+    base_block_id: 4,
+    hash: base_hashes[4],
+  };
+  assert!(
+    !reuse_block(&base, BLOCK_SIZE, &short, BLOCK_SIZE, &tmp_path)
+      .await
+      .unwrap()
+  );
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn reused_block_round_trip() {
+  let block = ReusedBlock {
+    block_id: 3,
+    base_block_id: 9,
+    hash: delta::hash_block(b"block"),
+  };
+  let mut bytes = vec![];
+  block.write_to(&mut bytes).await.unwrap();
+  assert_eq!(bytes.len(), 4 + 4 + delta::HASH_LEN);
+  assert_eq!(
+    ReusedBlock::read_from(&mut bytes.as_slice()).await.unwrap(),
+    block
+  );
+}
@@ -0,0 +1,363 @@
+use std::{
+  path::PathBuf,
+  sync::atomic::{AtomicU32, Ordering},
+};
+
+use rust_common::{
+  codec::CodecKind,
+  compress::Compression,
+  erasure::{decode_block, encode_block},
+  params::TransferParams,
+};
+use tokio::fs::{self, File};
+
+const PARAMS: TransferParams = TransferParams {
+  block_size: 256 * 1024,
+  symbol_size: 1024,
+  codec: CodecKind::RaptorQ,
+  source_blocks: 1,
+  encrypted: false,
+  compression: Compression::None,
+  interleave_depth: 1,
+};
+
+const FEC_CODECS: [CodecKind; 2] = [CodecKind::RaptorQ, CodecKind::ReedSolomon];
+
+fn with_codec(codec: CodecKind) -> TransferParams {
+  TransferParams { codec, ..PARAMS }
+}
+
+static CASE_ID: AtomicU32 = AtomicU32::new(0);
+
+fn case_dir() -> PathBuf {
+  std::env::temp_dir().join(format!(
+    "qft-erasure-{}-{}",
+    std::process::id(),
+    CASE_ID.fetch_add(1, Ordering::Relaxed)
+  ))
+}
+
+fn test_data(len: usize) -> Vec<u8> {
+  (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// Data which doesn't compress.
+fn noise(len: usize) -> Vec<u8> {
+  let mut state: u64 = 0x2545f4914f6cdd1d;
+  (0..len)
+    .map(|_| {
+      state ^= state << 13;
+      state ^= state >> 7;
+      state ^= state << 17;
+      state as u8
+    })
+    .collect()
+}
+
+/// Encode `len` bytes, drop the first `lost` packets of every block, decode and compare.
+/// Returns the number of packets sent.
+async fn round_trip(params: TransferParams, len: usize, lost: usize) -> usize {
+  round_trip_data(params, &test_data(len), lost).await
+}
+
+async fn round_trip_data(params: TransferParams, data: &[u8], lost: usize) -> usize {
+  let dir = case_dir();
+  fs::create_dir_all(&dir).await.unwrap();
+
+  let len = data.len();
+  let source_path = dir.join("source");
+  fs::write(&source_path, &data).await.unwrap();
+  let file = File::open(&source_path).await.unwrap();
+
+  let uuid = 1;
+  let tmp_base_path = dir.join("tmp").to_string_lossy().to_string();
+  let mut packet_count = 0;
+
+  let block_count = params.block_count(len as u64);
+  for block_id in 0..block_count {
+    let encoded = encode_block(&file, block_id, 0.1, &params, None)
+      .await
+      .unwrap();
+    packet_count += encoded.packets.len();
+
+    let encoded_len = encoded.encoded_len as u64;
+    let packets = encoded
+      .packets
+      .into_iter()
+      .skip(lost)
+      .map(Into::into)
+      .collect();
+    decode_block(
+      uuid,
+      block_id,
+      len as u64,
+      encoded_len,
+      &params,
+      packets,
+      &tmp_base_path,
+    )
+    .await
+    .unwrap();
+  }
+
+  let mut output = vec![];
+  for block_id in 0..block_count {
+    let path = PathBuf::from(&tmp_base_path)
+      .join(uuid.to_string())
+      .join(block_id.to_string());
+    output.extend(fs::read(path).await.unwrap());
+  }
+  assert_eq!(output, data);
+
+  fs::remove_dir_all(dir).await.unwrap();
+  packet_count
+}
+
+#[tokio::test]
+async fn empty_file_has_no_blocks() {
+  assert_eq!(PARAMS.block_count(0), 0);
+  assert_eq!(round_trip(with_codec(CodecKind::RaptorQ), 0, 0).await, 0);
+}
+
+#[tokio::test]
+async fn empty_block_is_rejected() {
+  let dir = case_dir();
+  fs::create_dir_all(&dir).await.unwrap();
+  fs::write(dir.join("source"), b"").await.unwrap();
+  let file = File::open(dir.join("source")).await.unwrap();
+
+  assert!(encode_block(&file, 0, 0.1, &PARAMS, None).await.is_err());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn one_byte_file() {
+  for codec in FEC_CODECS {
+    // One source symbol and one repair symbol
+    assert_eq!(round_trip(with_codec(codec), 1, 0).await, 2);
+    assert_eq!(round_trip(with_codec(codec), 1, 1).await, 2);
+  }
+}
+
+#[tokio::test]
+async fn small_file_only_sends_real_symbols() {
+  let len = 10 * 1024;
+  assert_eq!(PARAMS.block_source_symbols(len as u64, 0), 10);
+  for codec in FEC_CODECS {
+    assert_eq!(round_trip(with_codec(codec), len, 0).await, 10 + 1);
+    assert_eq!(round_trip(with_codec(codec), len, 1).await, 10 + 1);
+  }
+}
+
+#[tokio::test]
+async fn unaligned_last_symbol() {
+  for codec in FEC_CODECS {
+    round_trip(with_codec(codec), PARAMS.symbol_size as usize * 3 + 17, 1).await;
+  }
+}
+
+#[tokio::test]
+async fn exact_block_multiple() {
+  let len = PARAMS.block_size as usize * 2;
+  assert_eq!(PARAMS.block_count(len as u64), 2);
+  assert_eq!(PARAMS.block_len(len as u64, 1), PARAMS.block_size);
+  for codec in FEC_CODECS {
+    round_trip(with_codec(codec), len, 0).await;
+  }
+}
+
+#[tokio::test]
+async fn one_byte_over_block_multiple() {
+  let len = PARAMS.block_size as usize * 2 + 1;
+  assert_eq!(PARAMS.block_count(len as u64), 3);
+  assert_eq!(PARAMS.block_len(len as u64, 2), 1);
+  assert_eq!(PARAMS.block_source_symbols(len as u64, 2), 1);
+  // 2 full blocks with 10% parity, then 1 source and 1 repair symbol
+  for codec in FEC_CODECS {
+    assert_eq!(
+      round_trip(with_codec(codec), len, 0).await,
+      2 * (256 + 26) + 2
+    );
+  }
+}
+
+#[tokio::test]
+async fn one_byte_under_block_multiple() {
+  let len = PARAMS.block_size as usize - 1;
+  assert_eq!(PARAMS.block_count(len as u64), 1);
+  for codec in FEC_CODECS {
+    round_trip(with_codec(codec), len, 10).await;
+  }
+}
+
+#[tokio::test]
+async fn plain_codec_sends_source_symbols_only() {
+  let len = PARAMS.block_size as usize + 17;
+  assert_eq!(
+    round_trip(with_codec(CodecKind::Plain), len, 0).await,
+    256 + 1
+  );
+}
+
+#[tokio::test]
+async fn plain_codec_needs_every_symbol() {
+  let params = TransferParams {
+    codec: CodecKind::Plain,
+    ..PARAMS
+  };
+  let packets = params.codec.codec().encode(&params, &test_data(4096), 0.1);
+  let packets = packets.into_iter().skip(1).map(Into::into).collect();
+  assert!(params
+    .codec
+    .codec()
+    .decode(&params, 4096, packets)
+    .is_none());
+}
+
+#[test]
+fn unknown_source_block_is_ignored() {
+  for source_blocks in [1, 4] {
+    let params = TransferParams {
+      source_blocks,
+      ..PARAMS
+    };
+    let data = test_data(4096);
+    let packets = params.codec.codec().encode(&params, &data, 0.1);
+
+    // Malformed packets of source block 5 around the real ones
+    let mut malformed = packets[0].clone();
+    malformed[0] = 5;
+    let packets = std::iter::once(malformed.clone())
+      .chain(packets)
+      .chain(std::iter::once(malformed))
+      .map(Into::into)
+      .collect();
+    let decoded = params.codec.codec().decode(&params, 4096, packets);
+    assert_eq!(decoded, Some(data));
+  }
+}
+
+#[tokio::test]
+async fn partitioned_block_interleaves_source_blocks() {
+  let params = TransferParams {
+    source_blocks: 4,
+    ..PARAMS
+  };
+  let data = test_data(PARAMS.block_size as usize);
+  let packets = params.codec.codec().encode(&params, &data, 0.1);
+
+  // 64 source and 7 repair symbols per source block
+  assert_eq!(packets.len(), 4 * (64 + 7));
+  let source_block_numbers: Vec<u8> = packets.iter().take(8).map(|x| x[0]).collect();
+  assert_eq!(source_block_numbers, [0, 1, 2, 3, 0, 1, 2, 3]);
+}
+
+#[tokio::test]
+async fn partitioned_block_survives_burst_loss() {
+  let params = TransferParams {
+    source_blocks: 4,
+    ..PARAMS
+  };
+  // A burst of 24 packets only costs 6 symbols of each source block
+  round_trip(params, PARAMS.block_size as usize * 2, 24).await;
+}
+
+#[test]
+fn partitioning_from_block_length() {
+  // 131072 symbols, more than a single source block takes
+  let params = TransferParams {
+    source_blocks: 0,
+    block_size: 32 * 1024 * 1024,
+    symbol_size: 256,
+    ..PARAMS
+  };
+  assert!(params.validate().is_ok());
+  let data = noise(params.block_size as usize);
+  let packets = params.codec.codec().encode(&params, &data, 0.1);
+
+  let mut source_block_numbers: Vec<u8> = packets.iter().map(|x| x[0]).collect();
+  source_block_numbers.sort_unstable();
+  source_block_numbers.dedup();
+  assert!(source_block_numbers.len() > 1);
+
+  // A burst of 1000 packets spread over the source blocks
+  let packets = packets.into_iter().skip(1000).map(Into::into).collect();
+  let decoded = params
+    .codec
+    .codec()
+    .decode(&params, params.block_size, packets);
+  assert!(decoded == Some(data));
+}
+
+#[test]
+fn window_buffer_includes_packet_ids() {
+  let params = TransferParams {
+    source_blocks: 0,
+    block_size: 256 * 1024 * 1024,
+    interleave_depth: 4,
+    ..PARAMS
+  };
+  // 262144 symbols of 1024 bytes, each with a 4 byte packet ID, in each of 4 blocks
+  assert_eq!(params.window_buffer_size(), 4 * 262144 * 1028);
+  assert!(params.window_buffer_size() > 4 * params.block_size);
+}
+
+#[test]
+fn partitioning_requires_raptorq() {
+  let params = TransferParams {
+    source_blocks: 4,
+    ..with_codec(CodecKind::ReedSolomon)
+  };
+  assert!(params.validate().is_err());
+}
+
+#[tokio::test]
+async fn compressed_blocks_round_trip() {
+  let len = PARAMS.block_size as usize * 2 + 100;
+  let uncompressed = round_trip(PARAMS, len, 0).await;
+  for compression in [Compression::Zstd, Compression::Lz4] {
+    let params = TransferParams {
+      compression,
+      ..PARAMS
+    };
+    assert!(round_trip(params, len, 1).await < uncompressed / 4);
+  }
+}
+
+#[tokio::test]
+async fn incompressible_block_is_sent_as_is() {
+  let data = noise(PARAMS.block_size as usize + 10);
+  let params = TransferParams {
+    compression: Compression::Zstd,
+    ..PARAMS
+  };
+  assert_eq!(
+    round_trip_data(params, &data, 0).await,
+    round_trip_data(PARAMS, &data, 0).await
+  );
+}
+
+#[tokio::test]
+async fn invalid_encoded_len_is_rejected() {
+  let tmp_base_path = case_dir().to_string_lossy().to_string();
+  let result = decode_block(1, 0, 1000, 999, &PARAMS, vec![], &tmp_base_path).await;
+  assert!(result.is_err());
+
+  let params = TransferParams {
+    compression: Compression::Lz4,
+    ..PARAMS
+  };
+  let result = decode_block(1, 0, 1000, 1001, &params, vec![], &tmp_base_path).await;
+  assert!(result.is_err());
+}
+
+#[test]
+fn compression_excludes_encryption() {
+  let params = TransferParams {
+    compression: Compression::Zstd,
+    encrypted: true,
+    ..PARAMS
+  };
+  assert!(params.validate().is_err());
+}
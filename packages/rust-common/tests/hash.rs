@@ -0,0 +1,13 @@
+use rust_common::hash::file_hash;
+use tokio::fs;
+
+#[tokio::test]
+async fn file_hash_is_sha256() {
+  let path = std::env::temp_dir().join(format!("qft-file-hash-{}", std::process::id()));
+  fs::write(&path, b"abc").await.unwrap();
+  assert_eq!(
+    file_hash(&path).await.unwrap(),
+    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+  );
+  fs::remove_file(path).await.unwrap();
+}
@@ -0,0 +1,115 @@
+use rust_common::{
+  codec::CodecKind,
+  compress::Compression,
+  erasure::encode_block,
+  params::TransferParams,
+  pipeline::{encode_pipeline, stream_pipeline, StreamEvent},
+};
+use tokio::fs::{self, File};
+
+const PARAMS: TransferParams = TransferParams {
+  block_size: 256 * 1024,
+  symbol_size: 1024,
+  codec: CodecKind::ReedSolomon,
+  source_blocks: 1,
+  encrypted: false,
+  compression: Compression::None,
+  interleave_depth: 1,
+};
+
+#[tokio::test]
+async fn pipeline_yields_blocks_in_request_order() {
+  let dir = std::env::temp_dir().join(format!("qft-pipeline-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let path = dir.join("source");
+  let data: Vec<u8> = (0..PARAMS.block_size as usize * 3 + 100)
+    .map(|i| (i % 253) as u8)
+    .collect();
+  fs::write(&path, &data).await.unwrap();
+  let file = File::open(&path).await.unwrap();
+
+  let block_ids = vec![3, 0, 2, 1];
+  let mut rx = encode_pipeline(
+    file.try_clone().await.unwrap(),
+    block_ids.clone(),
+    0.1,
+    PARAMS,
+    None,
+    3,
+    1,
+  );
+
+  for block_id in block_ids {
+    let (encoded_id, packets) = rx.recv().await.unwrap().unwrap();
+    assert_eq!(encoded_id, block_id);
+    assert_eq!(
+      packets,
+      encode_block(&file, block_id, 0.1, &PARAMS, None)
+        .await
+        .unwrap()
+    );
+  }
+  assert!(rx.recv().await.is_none());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn pipeline_reports_encode_errors() {
+  let dir = std::env::temp_dir().join(format!("qft-pipeline-err-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let path = dir.join("source");
+  fs::write(&path, b"tiny").await.unwrap();
+  let file = File::open(&path).await.unwrap();
+
+  let mut rx = encode_pipeline(file, vec![0, 5], 0.1, PARAMS, None, 2, 2);
+  assert_eq!(rx.recv().await.unwrap().unwrap().0, 0);
+  assert!(rx.recv().await.unwrap().is_err());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn stream_announces_size_before_last_block() {
+  let dir = std::env::temp_dir().join(format!("qft-pipeline-stream-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let data: Vec<u8> = (0..PARAMS.block_size as usize * 2 + 100)
+    .map(|i| (i % 251) as u8)
+    .collect();
+  let spool_path = dir.join("spool");
+  let spool = File::create(&spool_path).await.unwrap();
+
+  let mut rx = stream_pipeline(std::io::Cursor::new(data.clone()), spool, 0.1, PARAMS, 1, 1);
+  let mut events = vec![];
+  while let Some(event) = rx.recv().await {
+    events.push(event.unwrap());
+  }
+
+  assert!(matches!(events[0], StreamEvent::Block(0, _)));
+  assert!(matches!(events[1], StreamEvent::Block(1, _)));
+  assert!(matches!(events[2], StreamEvent::End(size) if size == data.len() as u64));
+  let StreamEvent::Block(2, last) = &events[3] else {
+    panic!("last block missing");
+  };
+  assert_eq!(last.encoded_len, 100);
+  assert_eq!(events.len(), 4);
+  assert_eq!(fs::read(&spool_path).await.unwrap(), data);
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn empty_stream_only_ends() {
+  let dir = std::env::temp_dir().join(format!("qft-pipeline-empty-{}", std::process::id()));
+  fs::create_dir_all(&dir).await.unwrap();
+  let spool = File::create(dir.join("spool")).await.unwrap();
+
+  let mut rx = stream_pipeline(tokio::io::empty(), spool, 0.1, PARAMS, 2, 1);
+  assert!(matches!(
+    rx.recv().await.unwrap().unwrap(),
+    StreamEvent::End(0)
+  ));
+  assert!(rx.recv().await.is_none());
+
+  fs::remove_dir_all(dir).await.unwrap();
+}
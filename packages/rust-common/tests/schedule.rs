@@ -0,0 +1,46 @@
+use rust_common::schedule::Interleaver;
+
+fn packets(block_id: u32, count: u8) -> Vec<Vec<u8>> {
+  (0..count).map(|i| vec![block_id as u8, i]).collect()
+}
+
+fn drain(interleaver: &mut Interleaver) -> Vec<(u32, Vec<u8>)> {
+  std::iter::from_fn(|| interleaver.next_packet()).collect()
+}
+
+#[test]
+fn depth_one_sends_blocks_in_order() {
+  let mut interleaver = Interleaver::new(1);
+  interleaver.push(0, packets(0, 2));
+  assert!(interleaver.is_full());
+
+  assert_eq!(drain(&mut interleaver), [(0, vec![0, 0]), (0, vec![0, 1])]);
+  assert!(interleaver.is_empty());
+}
+
+#[test]
+fn packets_round_robin_over_window() {
+  let mut interleaver = Interleaver::new(3);
+  interleaver.push(0, packets(0, 2));
+  interleaver.push(1, packets(1, 1));
+  interleaver.push(2, packets(2, 3));
+
+  let block_ids: Vec<u32> = drain(&mut interleaver).into_iter().map(|x| x.0).collect();
+  assert_eq!(block_ids, [0, 1, 2, 0, 2, 2]);
+}
+
+#[test]
+fn finished_block_frees_window() {
+  let mut interleaver = Interleaver::new(2);
+  interleaver.push(0, packets(0, 1));
+  interleaver.push(1, packets(1, 2));
+  assert!(interleaver.is_full());
+
+  assert_eq!(interleaver.next_packet().unwrap().0, 0);
+  assert_eq!(interleaver.next_packet().unwrap().0, 1);
+  assert!(!interleaver.is_full());
+
+  interleaver.push(2, packets(2, 1));
+  let block_ids: Vec<u32> = drain(&mut interleaver).into_iter().map(|x| x.0).collect();
+  assert_eq!(block_ids, [1, 2]);
+}